@@ -0,0 +1,215 @@
+//! Test parameters the control protocol, statistics and link models need.
+//!
+//! The rest of the configuration, and the values of a run, live in the
+//! firmware's `config` module, which re-exports these.
+
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+/// Traffic pattern driven over the link while a test is running
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, TryFromPrimitive, IntoPrimitive)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum TestMode {
+    /// Central pings, peripheral answers using the selected [`PingPrimitives`]
+    #[default]
+    PingPong = 0x00,
+    /// Peripheral pings with the response primitive, central echoes with the request primitive
+    PeripheralPing = 0x01,
+    /// Central triggers with the request primitive, peripheral answers with a burst of notifications
+    Burst = 0x02,
+    /// Both sides send at random times under peripheral latency, each answers the other's data
+    PeripheralLatency = 0x03,
+}
+
+/// Level of log records to keep, ordered like `log::LevelFilter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, TryFromPrimitive, IntoPrimitive)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum LogLevel {
+    Off = 0x00,
+    Error = 0x01,
+    Warn = 0x02,
+    Info = 0x03,
+    Debug = 0x04,
+    Trace = 0x05,
+}
+
+/// ATT procedure the central uses to send a ping
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum PingRequest {
+    /// Write request, acknowledged by an ATT write response
+    Write = 0x00,
+    /// Write command, no ATT-level acknowledgement
+    WriteWithoutResponse = 0x01,
+    /// Read request on the counter characteristic, the read response is the pong
+    Read = 0x02,
+}
+
+/// ATT procedure the peripheral uses to answer a ping
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum PingResponse {
+    Notification = 0x00,
+    /// Confirmed by the central before the next indication can be sent
+    Indication = 0x01,
+}
+
+/// ATT primitives used in each direction of the ping-pong
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PingPrimitives {
+    pub request: PingRequest,
+    /// Unused with [`PingRequest::Read`]
+    pub response: PingResponse,
+}
+
+impl PingPrimitives {
+    pub const DEFAULT: Self = Self {
+        request: PingRequest::Write,
+        response: PingResponse::Notification,
+    };
+
+    /// Read polling cannot echo a ping or trigger a burst, and only
+    /// notifications can be queued back to back
+    pub const fn supports(&self, mode: TestMode) -> bool {
+        match mode {
+            TestMode::PingPong => true,
+            TestMode::PeripheralPing => !matches!(self.request, PingRequest::Read),
+            TestMode::Burst | TestMode::PeripheralLatency => {
+                !matches!(self.request, PingRequest::Read)
+                    && matches!(self.response, PingResponse::Notification)
+            }
+        }
+    }
+}
+
+/// Data channels the host classifies as usable, bit `n` is channel `n`
+///
+/// Anything outside the mask is reported to the controller as bad, which
+/// keeps it out of the channel map of connections the central creates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChannelMask(u64);
+
+impl ChannelMask {
+    /// Data channels 0..=36
+    pub const COUNT: u8 = 37;
+    pub const ALL: Self = Self((1 << Self::COUNT) - 1);
+    /// The controller needs at least this many channels left to hop on
+    pub const MIN_USED: u32 = 2;
+
+    /// Channels `first..=last` only
+    pub const fn range(first: u8, last: u8) -> Self {
+        Self(Self::ALL.0 & !((1 << first) - 1) & ((1 << (last + 1)) - 1))
+    }
+
+    /// Drop channels `first..=last`, e.g. the ones under a busy Wi-Fi channel
+    pub const fn without(self, first: u8, last: u8) -> Self {
+        Self(self.0 & !Self::range(first, last).0)
+    }
+
+    pub const fn is_used(self, channel: u8) -> bool {
+        channel < Self::COUNT && self.0 & (1 << channel) != 0
+    }
+
+    pub const fn count(self) -> u32 {
+        self.0.count_ones()
+    }
+}
+
+/// Burst traffic swept over continuation numbers and subrate factors
+///
+/// Each combination with a continuation number below its subrate factor is
+/// requested as a connection rate on the target interval and measured over
+/// `bursts_per_step` bursts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BurstConfig {
+    /// Notifications the peripheral queues per trigger
+    pub notifications: u8,
+    pub continuation_numbers: &'static [u16],
+    pub subrate_factors: &'static [u16],
+    pub bursts_per_step: u32,
+}
+
+impl BurstConfig {
+    pub const MAX_NOTIFICATIONS: u8 = 16;
+
+    /// The spec allows continuation numbers below the subrate factor only
+    pub const fn is_valid(subrate_factor: u16, continuation_number: u16) -> bool {
+        continuation_number < subrate_factor
+    }
+
+    /// Valid `(subrate_factor, continuation_number)` pairs, by subrate factor
+    pub fn combinations(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.subrate_factors
+            .iter()
+            .flat_map(|&s| self.continuation_numbers.iter().map(move |&c| (s, c)))
+            .filter(|&(s, c)| Self::is_valid(s, c))
+    }
+
+    /// Number of [`combinations`](Self::combinations)
+    pub const fn valid_combinations(&self) -> usize {
+        let mut count = 0;
+        let mut i = 0;
+        while i < self.subrate_factors.len() {
+            let mut j = 0;
+            while j < self.continuation_numbers.len() {
+                if Self::is_valid(self.subrate_factors[i], self.continuation_numbers[j]) {
+                    count += 1;
+                }
+                j += 1;
+            }
+            i += 1;
+        }
+        count
+    }
+}
+
+/// Side whose data a [`LatencyBudget`] covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Originator {
+    Central,
+    Peripheral,
+    Both,
+}
+
+/// Worst-case latency the application can live with and the traffic it expects
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyBudget {
+    /// From the moment data is queued until its last packet is received
+    pub worst_case_us: u32,
+    pub originator: Originator,
+    /// Packets queued together
+    pub packets_per_burst: u8,
+    pub bursts_per_second: u16,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_combinations_keep_continuation_below_subrate() {
+        let config = BurstConfig {
+            notifications: 4,
+            continuation_numbers: &[0, 1, 4],
+            subrate_factors: &[1, 2, 4],
+            bursts_per_step: 1,
+        };
+        let combinations: Vec<_> = config.combinations().collect();
+        assert_eq!(combinations, [(1, 0), (2, 0), (2, 1), (4, 0), (4, 1)]);
+        assert_eq!(config.valid_combinations(), combinations.len());
+
+        let none = BurstConfig {
+            subrate_factors: &[1],
+            continuation_numbers: &[1, 4],
+            ..config
+        };
+        assert_eq!(none.combinations().count(), 0);
+        assert_eq!(none.valid_combinations(), 0);
+    }
+}
@@ -0,0 +1,746 @@
+//! Control-point protocol used to drive the test over GATT.
+//!
+//! Requests are written to the control-point characteristic as
+//! `[opcode, payload...]`, all multi-byte fields little endian. Every request
+//! is answered with an indication `[RESPONSE_OPCODE, opcode, result, payload...]`.
+//!
+//! | Opcode | Request                 | Request payload                  | Response payload     |
+//! |--------|-------------------------|----------------------------------|----------------------|
+//! | `0x01` | Reset counters          | -                                | -                    |
+//! | `0x02` | Start test              | -                                | -                    |
+//! | `0x03` | Stop test               | -                                | -                    |
+//! | `0x04` | Select mode             | `mode: u8` ([`TestMode`])        | -                    |
+//! | `0x05` | Request connection rate | [`Command::RequestConnectionRate`] | -                  |
+//! | `0x06` | Fetch stats             | -                                | [`StatsSummary`]     |
+//! | `0x07` | Select ping primitives  | `request: u8`, `response: u8`    | -                    |
+//! | `0x08` | Set TX power            | `tx_power_dbm: i8`               | `selected_dbm: i8`   |
+//! | `0x09` | Set burst size          | `notifications: u8`              | -                    |
+//! | `0x0a` | Fetch RTT distribution  | -                                | [`RttDistribution`]  |
+//! | `0x0b` | Fetch interval group    | `index: u8`                      | [`IntervalPage`]     |
+//! | `0x0c` | Set log level           | `level: u8` ([`LogLevel`])       | -                    |
+
+use embassy_time::Duration;
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use thiserror::Error;
+
+use crate::config::{BurstConfig, LogLevel, PingPrimitives, PingRequest, PingResponse, TestMode};
+use crate::intervals::IntervalGroup;
+use crate::rate::RateParams;
+
+/// Size of the control-point characteristic value (fits the default ATT MTU)
+pub const CONTROL_POINT_LEN: usize = 20;
+
+/// First byte of every control-point indication
+pub const RESPONSE_OPCODE: u8 = 0x80;
+
+/// Connection rate request payload: 9 × u16
+const RATE_PAYLOAD_LEN: usize = 18;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Opcode {
+    ResetCounters = 0x01,
+    StartTest = 0x02,
+    StopTest = 0x03,
+    SelectMode = 0x04,
+    RequestConnectionRate = 0x05,
+    FetchStats = 0x06,
+    SelectPrimitives = 0x07,
+    SetTxPower = 0x08,
+    SetBurstSize = 0x09,
+    FetchRtt = 0x0A,
+    FetchIntervals = 0x0B,
+    SetLogLevel = 0x0C,
+}
+
+/// Result code carried in the third byte of a response
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
+#[repr(u8)]
+pub enum ResultCode {
+    Success = 0x01,
+    OpcodeNotSupported = 0x02,
+    InvalidParameter = 0x03,
+    OperationFailed = 0x04,
+    InvalidState = 0x05,
+}
+
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ControlError {
+    #[error("empty control-point write")]
+    Empty,
+    #[error("opcode {0:#04x} not supported")]
+    UnsupportedOpcode(u8),
+    #[error("invalid payload length for {0:?}")]
+    InvalidLength(Opcode),
+    #[error("invalid parameter for {0:?}")]
+    InvalidParameter(Opcode),
+    #[error("{0:?} not allowed in the current state")]
+    InvalidState(Opcode),
+    #[error("{0:?} failed")]
+    Failed(Opcode),
+    #[error("malformed control-point response")]
+    MalformedResponse,
+}
+
+impl ControlError {
+    pub fn result_code(&self) -> ResultCode {
+        match self {
+            ControlError::Empty | ControlError::UnsupportedOpcode(_) => {
+                ResultCode::OpcodeNotSupported
+            }
+            ControlError::InvalidLength(_) | ControlError::InvalidParameter(_) => {
+                ResultCode::InvalidParameter
+            }
+            ControlError::InvalidState(_) => ResultCode::InvalidState,
+            ControlError::Failed(_) | ControlError::MalformedResponse => {
+                ResultCode::OperationFailed
+            }
+        }
+    }
+
+    /// Raw opcode the failed request carried, `0` for an empty write
+    pub fn opcode(&self) -> u8 {
+        match *self {
+            ControlError::Empty | ControlError::MalformedResponse => 0,
+            ControlError::UnsupportedOpcode(op) => op,
+            ControlError::InvalidLength(op)
+            | ControlError::InvalidParameter(op)
+            | ControlError::InvalidState(op)
+            | ControlError::Failed(op) => op.into(),
+        }
+    }
+}
+
+/// Decoded control-point request
+pub enum Command {
+    ResetCounters,
+    StartTest,
+    StopTest,
+    SelectMode(TestMode),
+    /// Payload (u16 each): min/max interval in 125 µs units, subrate min/max,
+    /// max latency, continuation number, supervision timeout in 10 ms units,
+    /// min/max CE length in 125 µs units.
+    /// Ranges out of order or a subrate factor of 0 are an invalid parameter.
+    RequestConnectionRate(RateParams),
+    FetchStats,
+    /// Payload: [`PingRequest`], [`PingResponse`]
+    SelectPrimitives(PingPrimitives),
+    /// TX power of the connection in dBm, answered with the level the controller selected
+    SetTxPower(i8),
+    /// Notifications per trigger in [`TestMode::Burst`], 1 to [`BurstConfig::MAX_NOTIFICATIONS`]
+    SetBurstSize(u8),
+    /// Distribution of the round trips the peripheral initiated
+    FetchRtt,
+    /// One group of the peripheral's supported connection intervals, by index
+    FetchIntervals(u8),
+    /// Level for every module without its own filter
+    SetLogLevel(LogLevel),
+}
+
+impl Command {
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Command::ResetCounters => Opcode::ResetCounters,
+            Command::StartTest => Opcode::StartTest,
+            Command::StopTest => Opcode::StopTest,
+            Command::SelectMode(_) => Opcode::SelectMode,
+            Command::RequestConnectionRate(_) => Opcode::RequestConnectionRate,
+            Command::FetchStats => Opcode::FetchStats,
+            Command::SelectPrimitives(_) => Opcode::SelectPrimitives,
+            Command::SetTxPower(_) => Opcode::SetTxPower,
+            Command::SetBurstSize(_) => Opcode::SetBurstSize,
+            Command::FetchRtt => Opcode::FetchRtt,
+            Command::FetchIntervals(_) => Opcode::FetchIntervals,
+            Command::SetLogLevel(_) => Opcode::SetLogLevel,
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, ControlError> {
+        let (&op, payload) = data.split_first().ok_or(ControlError::Empty)?;
+        let opcode = Opcode::try_from(op).map_err(|_| ControlError::UnsupportedOpcode(op))?;
+
+        let expected_len = match opcode {
+            Opcode::SelectMode
+            | Opcode::SetTxPower
+            | Opcode::SetBurstSize
+            | Opcode::FetchIntervals
+            | Opcode::SetLogLevel => 1,
+            Opcode::SelectPrimitives => 2,
+            Opcode::RequestConnectionRate => RATE_PAYLOAD_LEN,
+            _ => 0,
+        };
+        if payload.len() != expected_len {
+            return Err(ControlError::InvalidLength(opcode));
+        }
+
+        Ok(match opcode {
+            Opcode::ResetCounters => Command::ResetCounters,
+            Opcode::StartTest => Command::StartTest,
+            Opcode::StopTest => Command::StopTest,
+            Opcode::SelectMode => Command::SelectMode(
+                TestMode::try_from(payload[0])
+                    .map_err(|_| ControlError::InvalidParameter(opcode))?,
+            ),
+            Opcode::RequestConnectionRate => match decode_rate_params(payload) {
+                params if params.is_consistent() => Command::RequestConnectionRate(params),
+                _ => return Err(ControlError::InvalidParameter(opcode)),
+            },
+            Opcode::FetchStats => Command::FetchStats,
+            Opcode::SelectPrimitives => Command::SelectPrimitives(PingPrimitives {
+                request: PingRequest::try_from(payload[0])
+                    .map_err(|_| ControlError::InvalidParameter(opcode))?,
+                response: PingResponse::try_from(payload[1])
+                    .map_err(|_| ControlError::InvalidParameter(opcode))?,
+            }),
+            Opcode::SetTxPower => Command::SetTxPower(payload[0] as i8),
+            Opcode::SetBurstSize => match payload[0] {
+                n @ 1..=BurstConfig::MAX_NOTIFICATIONS => Command::SetBurstSize(n),
+                _ => return Err(ControlError::InvalidParameter(opcode)),
+            },
+            Opcode::FetchRtt => Command::FetchRtt,
+            Opcode::FetchIntervals => Command::FetchIntervals(payload[0]),
+            Opcode::SetLogLevel => Command::SetLogLevel(
+                LogLevel::try_from(payload[0])
+                    .map_err(|_| ControlError::InvalidParameter(opcode))?,
+            ),
+        })
+    }
+
+    /// Encode the request for writing to a peer's control point
+    pub fn encode(&self, buf: &mut [u8; CONTROL_POINT_LEN]) -> usize {
+        buf[0] = self.opcode().into();
+        match self {
+            Command::SelectMode(mode) => {
+                buf[1] = (*mode).into();
+                2
+            }
+            Command::SetTxPower(dbm) => {
+                buf[1] = *dbm as u8;
+                2
+            }
+            Command::SetLogLevel(level) => {
+                buf[1] = (*level).into();
+                2
+            }
+            Command::SetBurstSize(n) | Command::FetchIntervals(n) => {
+                buf[1] = *n;
+                2
+            }
+            Command::SelectPrimitives(ping) => {
+                buf[1] = ping.request.into();
+                buf[2] = ping.response.into();
+                3
+            }
+            Command::RequestConnectionRate(params) => {
+                encode_rate_params(params, &mut buf[1..1 + RATE_PAYLOAD_LEN]);
+                1 + RATE_PAYLOAD_LEN
+            }
+            _ => 1,
+        }
+    }
+}
+
+fn decode_rate_params(payload: &[u8]) -> RateParams {
+    let field = |i: usize| u16::from_le_bytes([payload[2 * i], payload[2 * i + 1]]);
+    let units_125us = |i: usize| Duration::from_micros(field(i) as u64 * 125);
+
+    RateParams {
+        min_connection_interval: units_125us(0),
+        max_connection_interval: units_125us(1),
+        subrate_min: field(2),
+        subrate_max: field(3),
+        max_latency: field(4),
+        continuation_number: field(5),
+        supervision_timeout: Duration::from_millis(field(6) as u64 * 10),
+        min_ce_length: units_125us(7),
+        max_ce_length: units_125us(8),
+    }
+}
+
+fn encode_rate_params(params: &RateParams, out: &mut [u8]) {
+    let fields = [
+        (params.min_connection_interval.as_micros() / 125) as u16,
+        (params.max_connection_interval.as_micros() / 125) as u16,
+        params.subrate_min,
+        params.subrate_max,
+        params.max_latency,
+        params.continuation_number,
+        (params.supervision_timeout.as_millis() / 10) as u16,
+        (params.min_ce_length.as_micros() / 125) as u16,
+        (params.max_ce_length.as_micros() / 125) as u16,
+    ];
+    for (chunk, value) in out.as_chunks_mut::<2>().0.iter_mut().zip(fields) {
+        *chunk = value.to_le_bytes();
+    }
+}
+
+/// Response payload of [`Opcode::FetchStats`]
+///
+/// Layout: `pings: u32`, `mode: u8`, `running: u8`, `rtt_mean_us: u32`,
+/// `rtt_p99_us: u32`, round trips being the ones the peripheral initiated
+pub struct StatsSummary {
+    pub pings: u32,
+    pub mode: TestMode,
+    pub running: bool,
+    pub rtt_mean_us: u32,
+    pub rtt_p99_us: u32,
+}
+
+impl StatsSummary {
+    pub const LEN: usize = 14;
+
+    pub fn encode(&self, out: &mut [u8]) {
+        out[0..4].copy_from_slice(&self.pings.to_le_bytes());
+        out[4] = self.mode.into();
+        out[5] = self.running as u8;
+        out[6..10].copy_from_slice(&self.rtt_mean_us.to_le_bytes());
+        out[10..14].copy_from_slice(&self.rtt_p99_us.to_le_bytes());
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, ControlError> {
+        let payload = payload
+            .get(..Self::LEN)
+            .ok_or(ControlError::MalformedResponse)?;
+        let u32_at = |i: usize| u32::from_le_bytes(payload[i..i + 4].try_into().unwrap());
+
+        Ok(Self {
+            pings: u32_at(0),
+            mode: TestMode::try_from(payload[4]).map_err(|_| ControlError::MalformedResponse)?,
+            running: payload[5] != 0,
+            rtt_mean_us: u32_at(6),
+            rtt_p99_us: u32_at(10),
+        })
+    }
+}
+
+/// Response payload of [`Opcode::FetchRtt`]
+///
+/// Layout: `count: u32`, `p50_us: u32`, `p99_us: u32`, `max_us: u32`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RttDistribution {
+    pub count: u32,
+    pub p50_us: u32,
+    pub p99_us: u32,
+    pub max_us: u32,
+}
+
+impl RttDistribution {
+    pub const LEN: usize = 16;
+
+    pub fn encode(&self, out: &mut [u8]) {
+        let fields = [self.count, self.p50_us, self.p99_us, self.max_us];
+        for (chunk, value) in out.as_chunks_mut::<4>().0.iter_mut().zip(fields) {
+            *chunk = value.to_le_bytes();
+        }
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, ControlError> {
+        let payload = payload
+            .get(..Self::LEN)
+            .ok_or(ControlError::MalformedResponse)?;
+        let u32_at = |i: usize| u32::from_le_bytes(payload[i..i + 4].try_into().unwrap());
+
+        Ok(Self {
+            count: u32_at(0),
+            p50_us: u32_at(4),
+            p99_us: u32_at(8),
+            max_us: u32_at(12),
+        })
+    }
+}
+
+/// Response payload of [`Opcode::FetchIntervals`]
+///
+/// Layout: `minimum: u8`, `num_groups: u8`, then the group as `min: u16`,
+/// `max: u16`, `stride: u16`, all in 125 µs units as in the HCI response
+#[derive(Debug, Clone, Copy)]
+pub struct IntervalPage {
+    pub minimum: u8,
+    pub num_groups: u8,
+    pub group: IntervalGroup,
+}
+
+impl IntervalPage {
+    pub const LEN: usize = 8;
+
+    pub fn encode(&self, out: &mut [u8]) {
+        out[0] = self.minimum;
+        out[1] = self.num_groups;
+        let fields = [self.group.min, self.group.max, self.group.stride];
+        for (chunk, value) in out[2..Self::LEN].as_chunks_mut().0.iter_mut().zip(fields) {
+            *chunk = value.to_le_bytes();
+        }
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, ControlError> {
+        let payload = payload
+            .get(..Self::LEN)
+            .ok_or(ControlError::MalformedResponse)?;
+        let u16_at = |i: usize| u16::from_le_bytes([payload[i], payload[i + 1]]);
+
+        Ok(Self {
+            minimum: payload[0],
+            num_groups: payload[1],
+            group: IntervalGroup {
+                min: u16_at(2),
+                max: u16_at(4),
+                stride: u16_at(6),
+            },
+        })
+    }
+}
+
+/// Indication sent in reply to a control-point request
+pub struct Response {
+    value: [u8; CONTROL_POINT_LEN],
+}
+
+impl Response {
+    pub fn success(opcode: Opcode) -> Self {
+        Self::new(opcode.into(), ResultCode::Success)
+    }
+
+    pub fn error(err: &ControlError) -> Self {
+        Self::new(err.opcode(), err.result_code())
+    }
+
+    pub fn stats(summary: &StatsSummary) -> Self {
+        let mut response = Self::success(Opcode::FetchStats);
+        summary.encode(&mut response.value[3..3 + StatsSummary::LEN]);
+        response
+    }
+
+    pub fn rtt(distribution: &RttDistribution) -> Self {
+        let mut response = Self::success(Opcode::FetchRtt);
+        distribution.encode(&mut response.value[3..3 + RttDistribution::LEN]);
+        response
+    }
+
+    pub fn intervals(page: &IntervalPage) -> Self {
+        let mut response = Self::success(Opcode::FetchIntervals);
+        page.encode(&mut response.value[3..3 + IntervalPage::LEN]);
+        response
+    }
+
+    pub fn tx_power(selected_dbm: i8) -> Self {
+        let mut response = Self::success(Opcode::SetTxPower);
+        response.value[3] = selected_dbm as u8;
+        response
+    }
+
+    fn new(opcode: u8, result: ResultCode) -> Self {
+        let mut value = [0; CONTROL_POINT_LEN];
+        value[0] = RESPONSE_OPCODE;
+        value[1] = opcode;
+        value[2] = result.into();
+        Self { value }
+    }
+
+    pub fn value(&self) -> &[u8; CONTROL_POINT_LEN] {
+        &self.value
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.value[3..]
+    }
+
+    /// Parse an indicated response, checking it answers the request with `opcode`
+    pub fn parse(data: &[u8], opcode: Opcode) -> Result<Self, ControlError> {
+        let [RESPONSE_OPCODE, op, result, ..] = data else {
+            return Err(ControlError::MalformedResponse);
+        };
+        if *op != u8::from(opcode) {
+            return Err(ControlError::MalformedResponse);
+        }
+
+        match ResultCode::try_from(*result).map_err(|_| ControlError::MalformedResponse)? {
+            ResultCode::Success => {
+                let mut value = [0; CONTROL_POINT_LEN];
+                let len = data.len().min(CONTROL_POINT_LEN);
+                value[..len].copy_from_slice(&data[..len]);
+                Ok(Self { value })
+            }
+            ResultCode::OpcodeNotSupported => Err(ControlError::UnsupportedOpcode(*op)),
+            ResultCode::InvalidParameter => Err(ControlError::InvalidParameter(opcode)),
+            ResultCode::OperationFailed => Err(ControlError::Failed(opcode)),
+            ResultCode::InvalidState => Err(ControlError::InvalidState(opcode)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: RateParams = RateParams {
+        min_connection_interval: Duration::from_micros(7500),
+        max_connection_interval: Duration::from_micros(15_000),
+        subrate_min: 1,
+        subrate_max: 4,
+        max_latency: 2,
+        continuation_number: 1,
+        supervision_timeout: Duration::from_millis(4000),
+        min_ce_length: Duration::from_ticks(0),
+        max_ce_length: Duration::from_micros(7500),
+    };
+
+    fn every_command() -> [Command; 12] {
+        [
+            Command::ResetCounters,
+            Command::StartTest,
+            Command::StopTest,
+            Command::SelectMode(TestMode::Burst),
+            Command::RequestConnectionRate(RATE),
+            Command::FetchStats,
+            Command::SelectPrimitives(PingPrimitives {
+                request: PingRequest::WriteWithoutResponse,
+                response: PingResponse::Indication,
+            }),
+            Command::SetTxPower(-8),
+            Command::SetBurstSize(BurstConfig::MAX_NOTIFICATIONS),
+            Command::FetchRtt,
+            Command::FetchIntervals(3),
+            Command::SetLogLevel(LogLevel::Debug),
+        ]
+    }
+
+    fn encoded(command: &Command) -> ([u8; CONTROL_POINT_LEN], usize) {
+        let mut buf = [0; CONTROL_POINT_LEN];
+        let len = command.encode(&mut buf);
+        (buf, len)
+    }
+
+    fn parse_error(data: &[u8]) -> ControlError {
+        match Command::parse(data) {
+            Ok(command) => panic!("{:?} parsed", command.opcode()),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn every_opcode_round_trips() {
+        let opcodes: Vec<u8> = every_command().iter().map(|c| c.opcode().into()).collect();
+        assert_eq!(opcodes, (0x01..=0x0c).collect::<Vec<_>>());
+
+        for command in every_command() {
+            let (buf, len) = encoded(&command);
+            let parsed = Command::parse(&buf[..len]).unwrap();
+            assert_eq!(parsed.opcode(), command.opcode());
+            assert_eq!(encoded(&parsed), (buf, len));
+        }
+    }
+
+    #[test]
+    fn payload_layouts() {
+        assert_eq!(encoded(&Command::SetTxPower(-8)).0[..2], [0x08, 0xf8]);
+        assert_eq!(
+            encoded(&Command::SelectMode(TestMode::PeripheralLatency)).0[..2],
+            [0x04, 0x03]
+        );
+        let (buf, len) = encoded(&Command::RequestConnectionRate(RATE));
+        assert_eq!(len, 1 + RATE_PAYLOAD_LEN);
+        assert_eq!(
+            buf[..len],
+            [
+                0x05, 60, 0, 120, 0, 1, 0, 4, 0, 2, 0, 1, 0, 0x90, 0x01, 0, 0, 60, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn rate_params_round_trip() {
+        let mut out = [0; RATE_PAYLOAD_LEN];
+        encode_rate_params(&RATE, &mut out);
+        assert_eq!(decode_rate_params(&out), RATE);
+
+        // The longest interval and timeout the fields can carry
+        let widest = RateParams {
+            max_connection_interval: Duration::from_micros(u16::MAX as u64 * 125),
+            supervision_timeout: Duration::from_millis(u16::MAX as u64 * 10),
+            ..RATE
+        };
+        encode_rate_params(&widest, &mut out);
+        assert_eq!(out[2..4], [0xff, 0xff]);
+        assert_eq!(decode_rate_params(&out), widest);
+    }
+
+    #[test]
+    fn short_and_oversized_payloads_are_rejected() {
+        for command in every_command() {
+            let opcode = command.opcode();
+            let (buf, len) = encoded(&command);
+            if len > 1 {
+                assert_eq!(
+                    parse_error(&buf[..len - 1]),
+                    ControlError::InvalidLength(opcode)
+                );
+            }
+            assert_eq!(
+                parse_error(&buf[..len + 1]),
+                ControlError::InvalidLength(opcode)
+            );
+        }
+        assert_eq!(
+            ControlError::InvalidLength(Opcode::SetTxPower).result_code(),
+            ResultCode::InvalidParameter
+        );
+    }
+
+    #[test]
+    fn empty_writes_and_unknown_opcodes_are_not_supported() {
+        assert_eq!(parse_error(&[]), ControlError::Empty);
+        assert_eq!(ControlError::Empty.opcode(), 0);
+
+        for op in [0x00, 0x0d, 0x80, 0xff] {
+            let err = parse_error(&[op, 0]);
+            assert_eq!(err, ControlError::UnsupportedOpcode(op));
+            assert_eq!(err.opcode(), op);
+            assert_eq!(err.result_code(), ResultCode::OpcodeNotSupported);
+        }
+    }
+
+    #[test]
+    fn out_of_range_values_are_invalid_parameters() {
+        let burst_size = BurstConfig::MAX_NOTIFICATIONS + 1;
+        for (data, opcode) in [
+            (&[0x04, 0x04][..], Opcode::SelectMode),
+            (&[0x07, 0x03, 0x00], Opcode::SelectPrimitives),
+            (&[0x07, 0x00, 0x02], Opcode::SelectPrimitives),
+            (&[0x09, 0x00], Opcode::SetBurstSize),
+            (&[0x09, burst_size], Opcode::SetBurstSize),
+            (&[0x0c, 0x06], Opcode::SetLogLevel),
+        ] {
+            assert_eq!(parse_error(data), ControlError::InvalidParameter(opcode));
+        }
+    }
+
+    #[test]
+    fn inconsistent_rates_are_invalid_parameters() {
+        let inconsistent = [
+            RateParams {
+                subrate_min: 0,
+                ..RATE
+            },
+            RateParams {
+                subrate_min: 5,
+                ..RATE
+            },
+            RateParams {
+                min_connection_interval: Duration::from_micros(20_000),
+                ..RATE
+            },
+            RateParams {
+                min_ce_length: Duration::from_micros(10_000),
+                ..RATE
+            },
+        ];
+        for params in inconsistent {
+            let (buf, len) = encoded(&Command::RequestConnectionRate(params));
+            assert_eq!(
+                parse_error(&buf[..len]),
+                ControlError::InvalidParameter(Opcode::RequestConnectionRate)
+            );
+        }
+
+        // Equal bounds are a consistent range
+        let fixed = RateParams {
+            subrate_min: 4,
+            max_connection_interval: RATE.min_connection_interval,
+            ..RATE
+        };
+        let (buf, len) = encoded(&Command::RequestConnectionRate(fixed));
+        assert!(Command::parse(&buf[..len]).is_ok());
+    }
+
+    #[test]
+    fn response_parse_maps_result_codes() {
+        for err in [
+            ControlError::UnsupportedOpcode(0x06),
+            ControlError::InvalidParameter(Opcode::FetchStats),
+            ControlError::InvalidState(Opcode::FetchStats),
+            ControlError::Failed(Opcode::FetchStats),
+        ] {
+            let response = Response::error(&err);
+            assert_eq!(
+                response.value()[..3],
+                [RESPONSE_OPCODE, 0x06, err.result_code().into()]
+            );
+            assert_eq!(
+                Response::parse(response.value(), Opcode::FetchStats).err(),
+                Some(err)
+            );
+        }
+
+        // A length error is answered as an invalid parameter
+        let response = Response::error(&ControlError::InvalidLength(Opcode::FetchStats));
+        assert_eq!(
+            Response::parse(response.value(), Opcode::FetchStats).err(),
+            Some(ControlError::InvalidParameter(Opcode::FetchStats))
+        );
+    }
+
+    #[test]
+    fn response_parse_rejects_malformed_responses() {
+        let success = *Response::success(Opcode::StartTest).value();
+        let mut unknown_result = success;
+        unknown_result[2] = 0x06;
+        let mut not_a_response = success;
+        not_a_response[0] = 0x02;
+
+        for data in [&success[..2], &unknown_result[..], &not_a_response[..]] {
+            assert_eq!(
+                Response::parse(data, Opcode::StartTest).err(),
+                Some(ControlError::MalformedResponse)
+            );
+        }
+        // Answers another request
+        assert_eq!(
+            Response::parse(&success, Opcode::StopTest).err(),
+            Some(ControlError::MalformedResponse)
+        );
+        assert!(Response::parse(&success[..3], Opcode::StartTest).is_ok());
+    }
+
+    #[test]
+    fn response_payloads_round_trip() {
+        let rtt = RttDistribution {
+            count: 100,
+            p50_us: 1023,
+            p99_us: 4095,
+            max_us: 5000,
+        };
+        let response = Response::parse(Response::rtt(&rtt).value(), Opcode::FetchRtt).unwrap();
+        let decoded = RttDistribution::decode(response.payload()).unwrap();
+        assert_eq!(
+            (
+                decoded.count,
+                decoded.p50_us,
+                decoded.p99_us,
+                decoded.max_us
+            ),
+            (100, 1023, 4095, 5000)
+        );
+
+        let summary = StatsSummary {
+            pings: 7,
+            mode: TestMode::PeripheralPing,
+            running: true,
+            rtt_mean_us: 900,
+            rtt_p99_us: 2047,
+        };
+        let response = Response::stats(&summary);
+        let decoded = StatsSummary::decode(response.payload()).unwrap();
+        assert_eq!(decoded.mode, TestMode::PeripheralPing);
+        assert!(decoded.running);
+        assert_eq!((decoded.pings, decoded.rtt_p99_us), (7, 2047));
+
+        let response = Response::tx_power(-12);
+        assert_eq!(response.payload()[0] as i8, -12);
+        assert_eq!(
+            RttDistribution::decode(&response.payload()[..RttDistribution::LEN - 1]).err(),
+            Some(ControlError::MalformedResponse)
+        );
+    }
+}
@@ -0,0 +1,291 @@
+//! Radio-on time and average current of a connection, estimated.
+//!
+//! Currents are nRF52840 product specification figures at 3 V with the DC/DC
+//! regulator and 0 dBm TX. Every connection event costs a fixed wake-up for
+//! the CPU and HFXO, the radio ramp-up, and an empty packet exchange. The
+//! peripheral also listens early by the window widening that both sleep
+//! clocks' drift since its last event calls for. Data packets ride the
+//! events that take place anyway as long as the CE length has room, and the
+//! rest need continuation events. Data the central sends under peripheral
+//! latency is assumed to wait for an event the peripheral listens to, so it
+//! doesn't add wake-ups. Nothing here touches the hardware, so the model runs
+//! on the host as well.
+
+use embassy_time::Duration;
+
+use crate::rate::RateParams;
+
+/// Radio TX at 0 dBm, µA
+const TX_UA: u64 = 4_800;
+const RX_1M_UA: u64 = 4_600;
+const RX_2M_UA: u64 = 5_200;
+/// CPU running from flash with the cache enabled, µA
+const CPU_UA: u64 = 3_300;
+/// HFXO running ahead of the event, µA
+const HFXO_UA: u64 = 250;
+/// System ON with full RAM retention and the RTC running, nA
+const SLEEP_NA: u64 = 3_160;
+
+/// Fast radio ramp-up
+const RAMP_US: u64 = 40;
+const T_IFS_US: u64 = 150;
+/// CPU time the controller spends on one event, estimated
+const CPU_US: u64 = 150;
+/// HFXO start-up ahead of one event, estimated
+const HFXO_US: u64 = 400;
+/// Fixed part of the window widening
+const WINDOW_JITTER_US: u64 = 16;
+
+/// Data PDU payload of a counter notification: L2CAP header, ATT opcode and handle, `u32` value
+pub const NOTIFICATION_PAYLOAD: u16 = 11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Phy {
+    M1,
+    M2,
+    /// LE Coded at S8
+    Coded,
+}
+
+impl Phy {
+    fn rx_ua(self) -> u64 {
+        match self {
+            Phy::M1 => RX_1M_UA,
+            Phy::M2 => RX_2M_UA,
+            // The product specification gives none, assumed to match 1M
+            Phy::Coded => RX_1M_UA,
+        }
+    }
+}
+
+/// Air time of one PDU with `payload` bytes: preamble, access address, header and CRC around it
+pub fn airtime_us(phy: Phy, payload: u16) -> u64 {
+    let pdu = 2 + payload as u64 + 3;
+    match phy {
+        Phy::M1 => (1 + 4 + pdu) * 8,
+        Phy::M2 => (2 + 4 + pdu) * 4,
+        // Preamble, then access address, CI and TERM1 at S8, the PDU and TERM2 at S8
+        Phy::Coded => 80 + 256 + 16 + 24 + pdu * 64 + 24,
+    }
+}
+
+/// A data PDU and its empty acknowledgement, both inter-frame spaces included
+pub fn packet_pair_us(phy: Phy, payload: u16) -> u64 {
+    airtime_us(phy, payload) + T_IFS_US + airtime_us(phy, 0) + T_IFS_US
+}
+
+/// Connection parameters and traffic to estimate the cost of
+#[derive(Debug, Clone, Copy)]
+pub struct LinkModel {
+    pub interval: Duration,
+    pub subrate: u16,
+    pub latency: u16,
+    pub phy: Phy,
+    /// Data PDU payload of each packet
+    pub payload: u16,
+    pub ce_length: Duration,
+    /// Data packets per second, both directions together
+    pub packets_per_second: u32,
+    /// Sleep clock accuracy of each side
+    pub sca_ppm: u16,
+}
+
+impl LinkModel {
+    /// Counter notifications on LE 2M at `params`, the PHY the bring-up selects
+    pub fn from_params(params: &RateParams, packets_per_second: u32, sca_ppm: u16) -> Self {
+        Self {
+            interval: params.min_connection_interval,
+            subrate: params.subrate_max,
+            latency: params.max_latency,
+            phy: Phy::M2,
+            payload: NOTIFICATION_PAYLOAD,
+            ce_length: params.max_ce_length,
+            packets_per_second,
+            sca_ppm,
+        }
+    }
+}
+
+/// Radio-on time and average current of each side
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EnergyEstimate {
+    /// Radio on per second, µs
+    pub central_radio_us: u32,
+    pub peripheral_radio_us: u32,
+    /// Average current, nA
+    pub central_na: u32,
+    pub peripheral_na: u32,
+}
+
+impl EnergyEstimate {
+    /// Both sides' average current, nA
+    pub fn total_na(&self) -> u32 {
+        self.central_na + self.peripheral_na
+    }
+
+    pub fn log(&self) {
+        info!(
+            "  est. radio on: central {}us/s, peripheral {}us/s; current: central {}.{:02}uA, peripheral {}.{:02}uA",
+            self.central_radio_us,
+            self.peripheral_radio_us,
+            self.central_na / 1000,
+            self.central_na % 1000 / 10,
+            self.peripheral_na / 1000,
+            self.peripheral_na % 1000 / 10
+        );
+    }
+}
+
+/// Radio-on time and charge of one event, µs and pC
+#[derive(Clone, Copy)]
+struct Event {
+    radio_us: u64,
+    charge_pc: u64,
+}
+
+impl Event {
+    /// Ramp-up, then `tx_us` and `rx_us` of radio activity, on top of the fixed wake-up
+    fn new(ramp_ua: u64, tx_us: u64, rx_us: u64, rx_ua: u64) -> Self {
+        Self {
+            radio_us: RAMP_US + tx_us + rx_us,
+            charge_pc: RAMP_US * ramp_ua
+                + tx_us * TX_UA
+                + rx_us * rx_ua
+                + CPU_US * CPU_UA
+                + HFXO_US * HFXO_UA,
+        }
+    }
+
+    /// `count` of these events per second, as µs/s and pC/s
+    fn per_second(self, count_x1e6: u64) -> (u64, u64) {
+        (
+            self.radio_us * count_x1e6 / 1_000_000,
+            self.charge_pc * count_x1e6 / 1_000_000,
+        )
+    }
+}
+
+pub fn estimate(link: &LinkModel) -> EnergyEstimate {
+    let rx_ua = link.phy.rx_ua();
+    let empty_us = airtime_us(link.phy, 0);
+    let interval_us = link.interval.as_micros().max(1);
+    let central_gap_us = interval_us * link.subrate.max(1) as u64;
+    let peripheral_gap_us = central_gap_us * (link.latency as u64 + 1);
+
+    // The central sends first, the peripheral listens first and for longer the longer it slept
+    let central = Event::new(TX_UA, empty_us, T_IFS_US + empty_us, rx_ua);
+    let widening_us = 2 * link.sca_ppm as u64 * peripheral_gap_us / 1_000_000 + WINDOW_JITTER_US;
+    let peripheral = Event::new(rx_ua, empty_us, widening_us + empty_us + T_IFS_US, rx_ua);
+    // Continuation events follow an event closely, the peripheral needs no widening
+    let continuation = Event::new(rx_ua, empty_us, empty_us + T_IFS_US, rx_ua);
+
+    // Events per second, scaled by a million to keep the fractions
+    let central_events = 1_000_000_000_000 / central_gap_us;
+    let peripheral_events = 1_000_000_000_000 / peripheral_gap_us;
+
+    let pair_us = packet_pair_us(link.phy, link.payload);
+    let per_event = (link.ce_length.as_micros() / pair_us).max(1);
+    let packets = link.packets_per_second as u64;
+    let overflow = (packets * 1_000_000).saturating_sub(central_events * per_event);
+    let continuation_events = overflow.div_ceil(per_event);
+
+    // Each side sends about half the data and receives the other half
+    let data_us = packets * pair_us;
+    let data_pc = data_us * (TX_UA + rx_ua) / 2;
+
+    let (c_radio, c_pc) = central.per_second(central_events);
+    let (p_radio, p_pc) = peripheral.per_second(peripheral_events);
+    let (x_radio, x_pc) = continuation.per_second(continuation_events);
+
+    EnergyEstimate {
+        central_radio_us: (c_radio + x_radio + data_us) as u32,
+        peripheral_radio_us: (p_radio + x_radio + data_us) as u32,
+        central_na: ((c_pc + x_pc + data_pc) / 1000 + SLEEP_NA) as u32,
+        peripheral_na: ((p_pc + x_pc + data_pc) / 1000 + SLEEP_NA) as u32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn idle(phy: Phy) -> LinkModel {
+        LinkModel {
+            interval: Duration::from_micros(1_000_000),
+            subrate: 1,
+            latency: 0,
+            phy,
+            payload: NOTIFICATION_PAYLOAD,
+            ce_length: Duration::from_micros(0),
+            packets_per_second: 0,
+            sca_ppm: 0,
+        }
+    }
+
+    #[test]
+    fn airtime() {
+        assert_eq!(airtime_us(Phy::M1, 0), 80);
+        assert_eq!(airtime_us(Phy::M1, NOTIFICATION_PAYLOAD), 168);
+        assert_eq!(airtime_us(Phy::M2, 0), 44);
+        assert_eq!(airtime_us(Phy::M2, NOTIFICATION_PAYLOAD), 88);
+        assert_eq!(airtime_us(Phy::Coded, 0), 720);
+        assert_eq!(airtime_us(Phy::Coded, NOTIFICATION_PAYLOAD), 1424);
+        assert_eq!(packet_pair_us(Phy::M2, NOTIFICATION_PAYLOAD), 432);
+        assert_eq!(packet_pair_us(Phy::Coded, NOTIFICATION_PAYLOAD), 2444);
+    }
+
+    /// One empty exchange a second, the peripheral widening by the jitter only
+    #[test]
+    fn idle_link_per_phy() {
+        for (phy, radio_us, current_na) in [
+            (Phy::M1, (350, 366), (5_389, 5_454)),
+            (Phy::M2, (278, 294), (5_167, 5_266)),
+            (Phy::Coded, (1_630, 1_646), (11_405, 11_470)),
+        ] {
+            let e = estimate(&idle(phy));
+            assert_eq!(
+                (e.central_radio_us, e.peripheral_radio_us),
+                radio_us,
+                "{phy:?}"
+            );
+            assert_eq!((e.central_na, e.peripheral_na), current_na, "{phy:?}");
+        }
+    }
+
+    #[test]
+    fn traffic_overflows_into_continuation_events() {
+        // 100 events/s carry 2 pairs each, 800 packets need 400 continuation events,
+        // the peripheral listens at 20 events/s with 21 µs widening
+        let link = LinkModel {
+            interval: Duration::from_micros(10_000),
+            latency: 4,
+            ce_length: Duration::from_micros(1_000),
+            packets_per_second: 1_000,
+            sca_ppm: 50,
+            ..idle(Phy::M2)
+        };
+        let e = estimate(&link);
+        assert_eq!(
+            (e.central_radio_us, e.peripheral_radio_us),
+            (571_000, 549_180)
+        );
+        assert_eq!((e.central_na, e.peripheral_na), (3_173_060, 3_015_004));
+    }
+
+    #[test]
+    fn sleeping_longer_costs_less() {
+        let awake = estimate(&idle(Phy::M2));
+        let subrated = estimate(&LinkModel {
+            subrate: 4,
+            ..idle(Phy::M2)
+        });
+        let latent = estimate(&LinkModel {
+            latency: 4,
+            ..idle(Phy::M2)
+        });
+        assert!(subrated.total_na() < awake.total_na());
+        assert!(latent.peripheral_na < awake.peripheral_na);
+        assert_eq!(latent.central_na, awake.central_na);
+    }
+}
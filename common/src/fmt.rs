@@ -0,0 +1,81 @@
+//! Logging macros forwarding to `log` or `defmt`, selected by cargo feature.
+//!
+//! Exported for the firmware as well, the backend is the one this crate is
+//! built with, so the firmware selects it through `common/log` or
+//! `common/defmt`. Format strings must stay within the syntax both accept,
+//! `{}` and `{:?}` plus the integer hints `defmt` supports (e.g. `{:#010x}`).
+//! Values logged with the `defmt` backend need a `defmt::Format` impl, and the
+//! crate logging them a direct `defmt` dependency.
+#![macro_use]
+
+#[cfg(all(feature = "log", feature = "defmt"))]
+compile_error!("enable only one of the logging features: `log` or `defmt`");
+
+#[cfg(feature = "defmt")]
+#[doc(hidden)]
+pub use defmt;
+#[cfg(feature = "log")]
+#[doc(hidden)]
+pub use log;
+
+#[cfg(feature = "log")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log {
+    ($level:ident, $s:literal $(, $x:expr)* $(,)?) => {
+        $crate::fmt::log::$level!($s $(, $x)*)
+    };
+}
+
+#[cfg(feature = "defmt")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log {
+    ($level:ident, $s:literal $(, $x:expr)* $(,)?) => {
+        $crate::fmt::defmt::$level!($s $(, $x)*)
+    };
+}
+
+#[cfg(not(any(feature = "log", feature = "defmt")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log {
+    ($level:ident, $s:literal $(, $x:expr)* $(,)?) => {{
+        let _ = ($(&$x,)*);
+    }};
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($t:tt)*) => {
+        $crate::__log!(trace, $($t)*)
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($t:tt)*) => {
+        $crate::__log!(debug, $($t)*)
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($t:tt)*) => {
+        $crate::__log!(info, $($t)*)
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($t:tt)*) => {
+        $crate::__log!(warn, $($t)*)
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($t:tt)*) => {
+        $crate::__log!(error, $($t)*)
+    };
+}
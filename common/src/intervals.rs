@@ -0,0 +1,297 @@
+//! Connection intervals the controller supports.
+//!
+//! LE Read Minimum Supported Connection Interval returns the shortest interval
+//! the controller supports and groups of intervals `min..=max` in steps of
+//! `stride`, all in 125 µs units. [`SupportedIntervals::decode`] turns its
+//! return parameters into a table the rate logic can check requests against.
+//! The peripheral reports its own table a group at a time over the control
+//! point, which the central rebuilds with [`SupportedIntervals::push`].
+
+use embassy_time::Duration;
+use thiserror::Error;
+
+/// Unit of every interval in the response
+pub const UNIT_US: u64 = 125;
+
+/// Groups kept from one response, the SDC reports a handful at most
+const GROUPS_MAX: usize = 8;
+
+/// Length of one group: `min: u16`, `max: u16`, `stride: u16`
+const GROUP_LEN: usize = 6;
+
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IntervalsError {
+    #[error("response ends before its group list")]
+    Truncated,
+    #[error("{0} interval groups, at most {GROUPS_MAX} are supported")]
+    TooManyGroups(u8),
+    #[error("interval group {0} ends below its minimum")]
+    InvalidGroup(u8),
+}
+
+/// Intervals `min..=max` in steps of `stride`, in 125 µs units
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct IntervalGroup {
+    pub min: u16,
+    pub max: u16,
+    pub stride: u16,
+}
+
+impl IntervalGroup {
+    pub fn contains(&self, units: u16) -> bool {
+        if units < self.min || units > self.max {
+            return false;
+        }
+        // A zero stride only allows the minimum
+        match self.stride {
+            0 => units == self.min,
+            stride => (units - self.min).is_multiple_of(stride),
+        }
+    }
+
+    /// Number of intervals in the group
+    pub fn count(&self) -> u16 {
+        match self.stride {
+            0 => 1,
+            stride => self.max.saturating_sub(self.min) / stride + 1,
+        }
+    }
+
+    /// The group's intervals from shortest to longest
+    pub fn iter(&self) -> impl Iterator<Item = Duration> {
+        let group = *self;
+        (0..self.count()).map(move |i| to_duration(group.min + i * group.stride))
+    }
+}
+
+/// Decoded response of LE Read Minimum Supported Connection Interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SupportedIntervals {
+    /// Shortest supported interval, in 125 µs units
+    pub minimum: u16,
+    groups: [IntervalGroup; GROUPS_MAX],
+    len: usize,
+}
+
+impl SupportedIntervals {
+    /// Decode the return parameters after the status:
+    /// `minimum: u8`, `num_groups: u8`, then `num_groups` times `min: u16`,
+    /// `max: u16`, `stride: u16`
+    pub fn decode(params: &[u8]) -> Result<Self, IntervalsError> {
+        let [minimum, num_groups, groups @ ..] = params else {
+            return Err(IntervalsError::Truncated);
+        };
+        let len = *num_groups as usize;
+        if len > GROUPS_MAX {
+            return Err(IntervalsError::TooManyGroups(*num_groups));
+        }
+        if groups.len() < len * GROUP_LEN {
+            return Err(IntervalsError::Truncated);
+        }
+
+        let mut out = Self::new(*minimum as u16);
+        for raw in groups.as_chunks::<GROUP_LEN>().0.iter().take(len) {
+            let u16_at = |i: usize| u16::from_le_bytes([raw[i], raw[i + 1]]);
+            out.push(IntervalGroup {
+                min: u16_at(0),
+                max: u16_at(2),
+                stride: u16_at(4),
+            })?;
+        }
+        Ok(out)
+    }
+
+    /// Table without groups yet, `minimum` in 125 µs units
+    pub fn new(minimum: u16) -> Self {
+        Self {
+            minimum,
+            groups: [IntervalGroup::default(); GROUPS_MAX],
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, group: IntervalGroup) -> Result<(), IntervalsError> {
+        if group.min > group.max {
+            return Err(IntervalsError::InvalidGroup(self.len as u8));
+        }
+        let slot = self
+            .groups
+            .get_mut(self.len)
+            .ok_or(IntervalsError::TooManyGroups(self.len as u8 + 1))?;
+        *slot = group;
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn minimum(&self) -> Duration {
+        to_duration(self.minimum)
+    }
+
+    pub fn groups(&self) -> &[IntervalGroup] {
+        &self.groups[..self.len]
+    }
+
+    /// Whether the controller can run a connection at `interval`
+    pub fn supports(&self, interval: Duration) -> bool {
+        let us = interval.as_micros();
+        if !us.is_multiple_of(UNIT_US) || us < self.minimum().as_micros() {
+            return false;
+        }
+        let Ok(units) = u16::try_from(us / UNIT_US) else {
+            return false;
+        };
+        self.groups().iter().any(|g| g.contains(units))
+    }
+
+    /// Every supported interval from shortest to longest group
+    pub fn iter(&self) -> impl Iterator<Item = Duration> + '_ {
+        self.groups().iter().flat_map(IntervalGroup::iter)
+    }
+
+    pub fn log(&self) {
+        info!(
+            "Supported connection intervals, minimum {}us:",
+            self.minimum().as_micros()
+        );
+        for (i, g) in self.groups().iter().enumerate() {
+            info!(
+                "  group {}: {}us to {}us in steps of {}us ({} intervals)",
+                i,
+                to_duration(g.min).as_micros(),
+                to_duration(g.max).as_micros(),
+                g.stride as u64 * UNIT_US,
+                g.count()
+            );
+        }
+    }
+}
+
+fn to_duration(units: u16) -> Duration {
+    Duration::from_micros(units as u64 * UNIT_US)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimum 375 µs, then 375 µs to 3 ms in 125 µs steps and 3 ms to 400 ms in 1 ms steps
+    const RESPONSE: [u8; 14] = [
+        0x03, 0x02, // minimum, num_groups
+        0x03, 0x00, 0x18, 0x00, 0x01, 0x00, // 3..=24, stride 1
+        0x18, 0x00, 0x80, 0x0c, 0x08, 0x00, // 24..=3200, stride 8
+    ];
+
+    fn us(us: u64) -> Duration {
+        Duration::from_micros(us)
+    }
+
+    #[test]
+    fn decodes_response() {
+        let table = SupportedIntervals::decode(&RESPONSE).unwrap();
+        assert_eq!(table.minimum(), us(375));
+        assert_eq!(
+            table.groups(),
+            [
+                IntervalGroup {
+                    min: 3,
+                    max: 24,
+                    stride: 1
+                },
+                IntervalGroup {
+                    min: 24,
+                    max: 3200,
+                    stride: 8
+                },
+            ]
+        );
+        assert_eq!(table.groups()[0].count(), 22);
+        assert_eq!(table.groups()[1].count(), 398);
+
+        assert!(table.supports(us(375)));
+        assert!(table.supports(us(2000)));
+        assert!(table.supports(us(4000)));
+        assert!(table.supports(us(400_000)));
+        // Below the minimum, between strides, past the last group, off the 125 µs grid
+        assert!(!table.supports(us(250)));
+        assert!(!table.supports(us(3125)));
+        assert!(!table.supports(us(401_000)));
+        assert!(!table.supports(us(2010)));
+    }
+
+    #[test]
+    fn iterates_from_shortest() {
+        let table = SupportedIntervals::decode(&RESPONSE).unwrap();
+        let mut intervals = table.iter();
+        assert_eq!(intervals.next(), Some(us(375)));
+        assert_eq!(intervals.next(), Some(us(500)));
+        assert_eq!(table.iter().count(), 22 + 398);
+        assert_eq!(table.iter().last(), Some(us(400_000)));
+    }
+
+    #[test]
+    fn rejects_truncated_response() {
+        for len in [0, 1, 2, 7, RESPONSE.len() - 1] {
+            assert_eq!(
+                SupportedIntervals::decode(&RESPONSE[..len]),
+                Err(IntervalsError::Truncated),
+                "{len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_bytes_after_the_groups() {
+        let mut response = [0; RESPONSE.len() + 2];
+        response[..RESPONSE.len()].copy_from_slice(&RESPONSE);
+        let table = SupportedIntervals::decode(&response).unwrap();
+        assert_eq!(table.groups().len(), 2);
+    }
+
+    #[test]
+    fn rejects_more_groups_than_kept() {
+        let mut response = [0; 2 + 9 * GROUP_LEN];
+        response[..2].copy_from_slice(&[0x03, 0x09]);
+        assert_eq!(
+            SupportedIntervals::decode(&response),
+            Err(IntervalsError::TooManyGroups(9))
+        );
+
+        let mut table = SupportedIntervals::new(3);
+        let group = IntervalGroup {
+            min: 3,
+            max: 3,
+            stride: 0,
+        };
+        for _ in 0..GROUPS_MAX {
+            table.push(group).unwrap();
+        }
+        assert_eq!(table.push(group), Err(IntervalsError::TooManyGroups(9)));
+    }
+
+    #[test]
+    fn zero_stride_is_the_minimum_only() {
+        let response = [0x03, 0x01, 0x10, 0x00, 0x18, 0x00, 0x00, 0x00];
+        let table = SupportedIntervals::decode(&response).unwrap();
+        let group = table.groups()[0];
+        assert_eq!(group.count(), 1);
+        assert!(group.contains(16));
+        assert!(!group.contains(17));
+        assert!(!group.contains(24));
+        assert_eq!(table.iter().collect::<Vec<_>>(), [us(2000)]);
+    }
+
+    #[test]
+    fn rejects_group_ending_below_its_minimum() {
+        let response = [
+            0x03, 0x02, // minimum, num_groups
+            0x03, 0x00, 0x18, 0x00, 0x01, 0x00, // 3..=24
+            0x20, 0x00, 0x18, 0x00, 0x08, 0x00, // 32..=24
+        ];
+        assert_eq!(
+            SupportedIntervals::decode(&response),
+            Err(IntervalsError::InvalidGroup(1))
+        );
+    }
+}
@@ -0,0 +1,17 @@
+//! Control protocol, statistics and link models of the firmware.
+//!
+//! Nothing here touches the hardware or the BLE stack, so it builds and runs
+//! its tests on the host as well: `cargo test` in this directory.
+#![cfg_attr(not(test), no_std)]
+
+// Must come first, the logging macros are textually scoped
+#[doc(hidden)]
+pub mod fmt;
+
+pub mod config;
+pub mod control;
+pub mod energy;
+pub mod intervals;
+pub mod rate;
+pub mod solver;
+pub mod stats;
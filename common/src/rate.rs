@@ -0,0 +1,30 @@
+//! Connection rate parameters as the control point and the solver carry them.
+
+use embassy_time::Duration;
+
+/// Parameters of an LE Connection Rate Request
+///
+/// The fields of the host stack's `ConnectRateParams`, which this crate stays
+/// independent of so it builds on the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateParams {
+    pub min_connection_interval: Duration,
+    pub max_connection_interval: Duration,
+    pub subrate_min: u16,
+    pub subrate_max: u16,
+    pub max_latency: u16,
+    pub continuation_number: u16,
+    pub supervision_timeout: Duration,
+    pub min_ce_length: Duration,
+    pub max_ce_length: Duration,
+}
+
+impl RateParams {
+    /// Every range in order and a subrate factor of at least 1
+    pub fn is_consistent(&self) -> bool {
+        self.subrate_min >= 1
+            && self.subrate_min <= self.subrate_max
+            && self.min_connection_interval <= self.max_connection_interval
+            && self.min_ce_length <= self.max_ce_length
+    }
+}
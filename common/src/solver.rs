@@ -0,0 +1,410 @@
+//! Connection parameters derived from a latency budget.
+//!
+//! [`solve`] walks every interval both controllers support and gives each the
+//! longest subrate and peripheral latency that still meet the
+//! [`LatencyBudget`]. The CE length and continuation number are sized to the
+//! declared bursts. Of the sets that fit, it picks the one with the lowest
+//! average current of both sides by the [`energy`] model, and reports what
+//! kept it from saving more. It only does arithmetic on the interval tables, so it behaves
+//! the same on the host as on the target.
+//!
+//! Worst cases per originator, for interval `I`, subrate `S` and latency `L`:
+//! central-originated data waits up to `I × S × (L + 1)` for an event the
+//! peripheral listens to, peripheral-originated data up to `I × S`. A burst
+//! that doesn't fit one event continues on the base interval.
+
+use embassy_time::Duration;
+use thiserror::Error;
+
+use crate::config::{LatencyBudget, Originator};
+use crate::energy::{self, EnergyEstimate, LinkModel, NOTIFICATION_PAYLOAD, Phy};
+use crate::intervals::{SupportedIntervals, UNIT_US};
+use crate::rate::RateParams;
+
+/// Upper bound of `subrate_factor × (max_latency + 1)`
+const SUBRATE_PRODUCT_MAX: u64 = 500;
+
+const SUPERVISION_TIMEOUT_MIN_US: u64 = 100_000;
+const SUPERVISION_TIMEOUT_MAX_US: u64 = 32_000_000;
+const SUPERVISION_TIMEOUT_UNIT_US: u64 = 10_000;
+
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SolveError {
+    #[error("no connection interval both controllers support")]
+    NoCommonInterval,
+    #[error("even the shortest common interval, {0}us, misses the budget")]
+    OverBudget(u64),
+}
+
+/// What kept the chosen set from sleeping longer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Limit {
+    /// A longer subrate or latency would miss the budget
+    Budget,
+    /// `subrate_factor × (max_latency + 1)` is at 500
+    SubrateProduct,
+    /// A longer latency would need a supervision timeout over 32 s
+    SupervisionTimeout,
+}
+
+/// The chosen parameter set and why
+#[derive(Debug, Clone, Copy)]
+pub struct Solution {
+    pub params: RateParams,
+    /// Worst case for the declared traffic
+    pub worst_case: Duration,
+    pub energy: EnergyEstimate,
+    pub limit: Limit,
+    /// Intervals that met the budget
+    pub candidates: u32,
+    /// Whether the peer's table narrowed the intervals
+    pub peer_checked: bool,
+}
+
+impl Solution {
+    fn cost(&self) -> u32 {
+        self.energy.total_na()
+    }
+
+    pub fn log(&self, budget: &LatencyBudget) {
+        let p = &self.params;
+        info!(
+            "Latency budget {}us for {:?}-originated bursts of {} at {}/s",
+            budget.worst_case_us,
+            budget.originator,
+            budget.packets_per_burst,
+            budget.bursts_per_second
+        );
+        info!(
+            "  chose interval {}us, subrate {}, latency {}, continuation {}, CE {}us, timeout {}ms",
+            p.min_connection_interval.as_micros(),
+            p.subrate_max,
+            p.max_latency,
+            p.continuation_number,
+            p.max_ce_length.as_micros(),
+            p.supervision_timeout.as_millis()
+        );
+        info!("  worst case {}us", self.worst_case.as_micros());
+        self.energy.log();
+        info!(
+            "  lowest current of {} intervals within budget ({}), limited by {:?}",
+            self.candidates,
+            if self.peer_checked {
+                "both tables"
+            } else {
+                "local table only"
+            },
+            self.limit
+        );
+    }
+}
+
+/// Pick the most power-efficient parameters that meet `budget`
+///
+/// Without the peer's table, the local one is assumed to hold for both sides.
+/// `sca_ppm` is the sleep clock accuracy of each side.
+pub fn solve(
+    budget: &LatencyBudget,
+    local: &SupportedIntervals,
+    peer: Option<&SupportedIntervals>,
+    sca_ppm: u16,
+) -> Result<Solution, SolveError> {
+    let mut best: Option<Solution> = None;
+    let mut shortest: Option<Duration> = None;
+    let mut candidates = 0;
+
+    let common = local
+        .iter()
+        .filter(|&interval| peer.is_none_or(|p| p.supports(interval)));
+    for interval in common {
+        shortest = Some(shortest.map_or(interval, |s| s.min(interval)));
+        let Some(solution) = evaluate(budget, interval, sca_ppm) else {
+            continue;
+        };
+        candidates += 1;
+        let better = best
+            .as_ref()
+            .is_none_or(|b| (solution.cost(), solution.worst_case) < (b.cost(), b.worst_case));
+        if better {
+            best = Some(solution);
+        }
+    }
+
+    let mut solution = match (best, shortest) {
+        (Some(best), _) => best,
+        (None, Some(shortest)) => return Err(SolveError::OverBudget(shortest.as_micros())),
+        (None, None) => return Err(SolveError::NoCommonInterval),
+    };
+    solution.candidates = candidates;
+    solution.peer_checked = peer.is_some();
+    Ok(solution)
+}
+
+/// The sleepiest set on `interval` that meets the budget, if any does
+fn evaluate(budget: &LatencyBudget, interval: Duration, sca_ppm: u16) -> Option<Solution> {
+    let interval_us = interval.as_micros();
+    let packets = budget.packets_per_burst.max(1) as u64;
+    let pair_us = energy::packet_pair_us(Phy::M2, NOTIFICATION_PAYLOAD);
+
+    // The whole burst in one event if the interval has room, the rest continues on the base interval
+    let (ce_us, extra_events) = match packets * pair_us {
+        air if air <= interval_us => (air.div_ceil(UNIT_US) * UNIT_US, 0),
+        _ => {
+            let per_event = (interval_us / pair_us).max(1);
+            (interval_us, packets.div_ceil(per_event) - 1)
+        }
+    };
+    let tail_us = extra_events * interval_us + ce_us;
+
+    // Room for the wait until the first event, in whole intervals
+    let product_max = (budget.worst_case_us as u64)
+        .checked_sub(tail_us)?
+        .checked_div(interval_us)?
+        .min(SUBRATE_PRODUCT_MAX);
+    if product_max == 0 {
+        return None;
+    }
+
+    // Most intervals the peripheral may stay away for with the timeout within 32 s
+    let by_timeout = (SUPERVISION_TIMEOUT_MAX_US - SUPERVISION_TIMEOUT_UNIT_US) / (2 * interval_us);
+    if by_timeout == 0 {
+        return None;
+    }
+
+    let (subrate, latency, limit) = match budget.originator {
+        // Latency delays the central's data as much as subrating, which also quiets the central
+        Originator::Central | Originator::Both => {
+            let product = product_max.min(by_timeout);
+            let limit = if by_timeout < product_max {
+                Limit::SupervisionTimeout
+            } else if product == SUBRATE_PRODUCT_MAX {
+                Limit::SubrateProduct
+            } else {
+                Limit::Budget
+            };
+            (product, 0, limit)
+        }
+        // The peripheral sends at any subrated event, latency only skips the ones it has nothing for
+        Originator::Peripheral => {
+            let subrate = product_max.min(by_timeout);
+            let by_product = SUBRATE_PRODUCT_MAX / subrate;
+            let by_timeout = by_timeout / subrate;
+            let limit = if by_product <= by_timeout {
+                Limit::SubrateProduct
+            } else {
+                Limit::SupervisionTimeout
+            };
+            (subrate, by_product.min(by_timeout).max(1) - 1, limit)
+        }
+    };
+
+    let wait_us = match budget.originator {
+        Originator::Central | Originator::Both => interval_us * subrate * (latency + 1),
+        Originator::Peripheral => interval_us * subrate,
+    };
+    // One continuation event keeps a burst on the base interval, the spec needs it below the subrate
+    let continuation = (extra_events > 0 && subrate > 1) as u64;
+
+    // Must exceed twice the longest time the peripheral may stay away
+    let away_us = 2 * interval_us * subrate * (latency + 1);
+    let timeout_us = (away_us / SUPERVISION_TIMEOUT_UNIT_US + 1) * SUPERVISION_TIMEOUT_UNIT_US;
+    let timeout_us = timeout_us.clamp(SUPERVISION_TIMEOUT_MIN_US, SUPERVISION_TIMEOUT_MAX_US);
+
+    let params = RateParams {
+        min_connection_interval: interval,
+        max_connection_interval: interval,
+        subrate_min: subrate as u16,
+        subrate_max: subrate as u16,
+        max_latency: latency as u16,
+        continuation_number: continuation as u16,
+        supervision_timeout: Duration::from_micros(timeout_us),
+        min_ce_length: Duration::from_micros(ce_us),
+        max_ce_length: Duration::from_micros(ce_us),
+    };
+    let packets_per_second = packets as u32 * budget.bursts_per_second as u32;
+    let link = LinkModel::from_params(&params, packets_per_second, sca_ppm);
+
+    Some(Solution {
+        params,
+        worst_case: Duration::from_micros(wait_us + tail_us),
+        energy: energy::estimate(&link),
+        limit,
+        candidates: 0,
+        peer_checked: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intervals::IntervalGroup;
+
+    fn table(groups: &[(u16, u16, u16)]) -> SupportedIntervals {
+        let mut table = SupportedIntervals::new(groups[0].0);
+        for &(min, max, stride) in groups {
+            table.push(IntervalGroup { min, max, stride }).unwrap();
+        }
+        table
+    }
+
+    /// 375 µs to 3 ms in 125 µs steps, then up to the 4 s spec maximum in 1 ms steps
+    fn full() -> SupportedIntervals {
+        table(&[(3, 24, 1), (24, 32000, 8)])
+    }
+
+    fn budget(worst_case_us: u32, originator: Originator) -> LatencyBudget {
+        LatencyBudget {
+            worst_case_us,
+            originator,
+            packets_per_burst: 1,
+            bursts_per_second: 1,
+        }
+    }
+
+    /// The spec's rule for the timeout, and its range
+    fn assert_timeout_valid(params: &RateParams) {
+        let away = params.min_connection_interval.as_micros()
+            * params.subrate_max as u64
+            * (params.max_latency as u64 + 1);
+        let timeout = params.supervision_timeout.as_micros();
+        assert!(timeout > 2 * away, "timeout {timeout}us, away {away}us");
+        assert!((SUPERVISION_TIMEOUT_MIN_US..=SUPERVISION_TIMEOUT_MAX_US).contains(&timeout));
+    }
+
+    #[test]
+    fn tightest_budget_takes_the_shortest_interval() {
+        // A notification pair doesn't fit 375 µs, the event runs to the interval's end
+        let solution = solve(&budget(750, Originator::Central), &full(), None, 50).unwrap();
+        let p = &solution.params;
+        assert_eq!(p.min_connection_interval, Duration::from_micros(375));
+        assert_eq!((p.subrate_max, p.max_latency), (1, 0));
+        assert_eq!(p.max_ce_length, Duration::from_micros(375));
+        assert_eq!(p.supervision_timeout, Duration::from_micros(100_000));
+        assert_eq!(solution.worst_case, Duration::from_micros(750));
+        assert_eq!(solution.limit, Limit::Budget);
+        assert_eq!(solution.candidates, 1);
+        assert!(!solution.peer_checked);
+    }
+
+    #[test]
+    fn budget_below_the_shortest_interval_is_over_budget() {
+        assert_eq!(
+            solve(&budget(749, Originator::Central), &full(), None, 50).unwrap_err(),
+            SolveError::OverBudget(375)
+        );
+        assert_eq!(
+            solve(&budget(0, Originator::Peripheral), &full(), None, 50).unwrap_err(),
+            SolveError::OverBudget(375)
+        );
+    }
+
+    #[test]
+    fn disjoint_tables_have_no_common_interval() {
+        let local = table(&[(6, 24, 2)]);
+        let peer = table(&[(3, 23, 2), (32, 3200, 8)]);
+        assert_eq!(
+            solve(
+                &budget(1_000_000, Originator::Both),
+                &local,
+                Some(&peer),
+                50
+            )
+            .unwrap_err(),
+            SolveError::NoCommonInterval
+        );
+    }
+
+    #[test]
+    fn peer_table_narrows_the_intervals() {
+        let peer = table(&[(3, 3, 0)]);
+        let solution = solve(&budget(750, Originator::Central), &full(), Some(&peer), 50).unwrap();
+        assert_eq!(
+            solution.params.min_connection_interval,
+            Duration::from_micros(375)
+        );
+        assert!(solution.peer_checked);
+    }
+
+    #[test]
+    fn widest_budget_stays_within_the_timeout() {
+        for originator in [
+            Originator::Central,
+            Originator::Peripheral,
+            Originator::Both,
+        ] {
+            let solution = solve(&budget(u32::MAX, originator), &full(), None, 50).unwrap();
+            assert_timeout_valid(&solution.params);
+            assert_eq!(solution.limit, Limit::SupervisionTimeout, "{originator:?}");
+        }
+    }
+
+    #[test]
+    fn longest_interval_is_bound_by_the_timeout() {
+        let interval = Duration::from_micros(4_000_000);
+        for originator in [Originator::Central, Originator::Peripheral] {
+            let solution = evaluate(&budget(u32::MAX, originator), interval, 50).unwrap();
+            let p = &solution.params;
+            // 2 × 4 s × 3 is the most below 32 s
+            assert_eq!((p.subrate_max, p.max_latency), (3, 0), "{originator:?}");
+            assert_eq!(p.supervision_timeout, Duration::from_micros(24_010_000));
+            assert_eq!(solution.limit, Limit::SupervisionTimeout);
+        }
+    }
+
+    #[test]
+    fn short_interval_is_bound_by_the_subrate_product() {
+        let interval = Duration::from_micros(7_500);
+        let central = evaluate(&budget(u32::MAX, Originator::Central), interval, 50).unwrap();
+        assert_eq!(
+            (central.params.subrate_max, central.params.max_latency),
+            (500, 0)
+        );
+        assert_eq!(central.limit, Limit::SubrateProduct);
+        assert_timeout_valid(&central.params);
+
+        // The whole product goes to the subrate, there is no room for latency
+        let peripheral = evaluate(&budget(u32::MAX, Originator::Peripheral), interval, 50).unwrap();
+        assert_eq!(
+            (peripheral.params.subrate_max, peripheral.params.max_latency),
+            (500, 0)
+        );
+        assert_eq!(peripheral.limit, Limit::SubrateProduct);
+    }
+
+    #[test]
+    fn every_candidate_has_a_valid_timeout() {
+        for worst_case_us in [750, 10_000, 100_000, 1_000_000, 10_000_000, 100_000_000] {
+            for originator in [
+                Originator::Central,
+                Originator::Peripheral,
+                Originator::Both,
+            ] {
+                let budget = budget(worst_case_us, originator);
+                for interval in full().iter() {
+                    if let Some(solution) = evaluate(&budget, interval, 50) {
+                        assert_timeout_valid(&solution.params);
+                        assert!(solution.worst_case.as_micros() <= worst_case_us as u64);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn long_burst_continues_on_the_base_interval() {
+        let budget = LatencyBudget {
+            packets_per_burst: 10,
+            ..budget(100_000, Originator::Peripheral)
+        };
+        // 432 µs per notification, two fit a 1 ms event
+        let solution = evaluate(&budget, Duration::from_micros(1_000), 50).unwrap();
+        let p = &solution.params;
+        assert_eq!(p.max_ce_length, Duration::from_micros(1_000));
+        assert_eq!(p.continuation_number, 1);
+        // Four more events for the rest of the burst
+        assert_eq!(p.subrate_max, 95);
+        assert_eq!(solution.worst_case, Duration::from_micros(100_000));
+    }
+}
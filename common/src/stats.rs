@@ -0,0 +1,542 @@
+//! On-device test statistics.
+//!
+//! Everything here is plain data so it can be encoded into the GATT results
+//! service as well as logged over RTT. All multi-byte fields of the encoded
+//! layouts are little endian.
+
+use embassy_time::Duration;
+
+use crate::config::ChannelMask;
+
+/// Number of log2 buckets, the last one collects everything above ~32 ms
+const HISTOGRAM_BUCKETS: usize = 16;
+
+/// Link-layer parameters currently in effect
+#[derive(Debug, Clone, Copy)]
+pub struct LinkParams {
+    pub interval: Duration,
+    pub peripheral_latency: u16,
+    /// 1 until subrating is seen to take effect
+    pub subrate_factor: u16,
+    pub supervision_timeout: Duration,
+    pub tx_phy: u8,
+    pub rx_phy: u8,
+}
+
+impl LinkParams {
+    /// `interval_us: u32`, `peripheral_latency: u16`, `subrate_factor: u16`,
+    /// `supervision_timeout_ms: u16`, `tx_phy: u8`, `rx_phy: u8`
+    pub const LEN: usize = 12;
+
+    pub const fn new() -> Self {
+        Self {
+            interval: Duration::from_ticks(0),
+            peripheral_latency: 0,
+            subrate_factor: 1,
+            supervision_timeout: Duration::from_ticks(0),
+            tx_phy: 0,
+            rx_phy: 0,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut out = [0; Self::LEN];
+        out[0..4].copy_from_slice(&(self.interval.as_micros() as u32).to_le_bytes());
+        out[4..6].copy_from_slice(&self.peripheral_latency.to_le_bytes());
+        out[6..8].copy_from_slice(&self.subrate_factor.to_le_bytes());
+        out[8..10].copy_from_slice(&(self.supervision_timeout.as_millis() as u16).to_le_bytes());
+        out[10] = self.tx_phy;
+        out[11] = self.rx_phy;
+        out
+    }
+}
+
+impl Default for LinkParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Latency histogram with power-of-two microsecond buckets
+///
+/// Bucket `i` counts samples in `[2^i, 2^(i+1))` µs, so percentiles are
+/// reported as the upper bound of the bucket they fall into.
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    buckets: [u32; HISTOGRAM_BUCKETS],
+    count: u32,
+    sum_us: u64,
+    min_us: u32,
+    max_us: u32,
+}
+
+impl LatencyHistogram {
+    pub const fn new() -> Self {
+        Self {
+            buckets: [0; HISTOGRAM_BUCKETS],
+            count: 0,
+            sum_us: 0,
+            min_us: u32::MAX,
+            max_us: 0,
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros().min(u32::MAX as u64) as u32;
+        let bucket = (u32::BITS - us.leading_zeros()).saturating_sub(1) as usize;
+
+        self.buckets[bucket.min(HISTOGRAM_BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.sum_us += us as u64;
+        self.min_us = self.min_us.min(us);
+        self.max_us = self.max_us.max(us);
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        if self.count == 0 {
+            return LatencySummary::default();
+        }
+
+        LatencySummary {
+            count: self.count,
+            min_us: self.min_us,
+            max_us: self.max_us,
+            mean_us: (self.sum_us / self.count as u64) as u32,
+            p50_us: self.percentile(50),
+            p99_us: self.percentile(99),
+        }
+    }
+
+    /// Upper bound of the bucket holding the given percentile, capped at the maximum seen
+    ///
+    /// The last bucket has no upper bound, so it reports the maximum.
+    fn percentile(&self, pct: u32) -> u32 {
+        let rank = (self.count as u64 * pct as u64).div_ceil(100).max(1);
+        let mut seen = 0u64;
+        for (i, &n) in self.buckets[..HISTOGRAM_BUCKETS - 1].iter().enumerate() {
+            seen += n as u64;
+            if seen >= rank {
+                return ((2u64 << i) - 1).min(self.max_us as u64) as u32;
+            }
+        }
+        self.max_us
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LatencySummary {
+    pub count: u32,
+    pub min_us: u32,
+    pub max_us: u32,
+    pub mean_us: u32,
+    pub p50_us: u32,
+    pub p99_us: u32,
+}
+
+impl LatencySummary {
+    /// `count`, `min_us`, `max_us`, `mean_us`, `p50_us`, `p99_us`, all `u32`
+    pub const LEN: usize = 24;
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut out = [0; Self::LEN];
+        let fields = [
+            self.count,
+            self.min_us,
+            self.max_us,
+            self.mean_us,
+            self.p50_us,
+            self.p99_us,
+        ];
+        for (chunk, value) in out.as_chunks_mut::<4>().0.iter_mut().zip(fields) {
+            *chunk = value.to_le_bytes();
+        }
+        out
+    }
+}
+
+/// Application-level packet counters
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PacketCounters {
+    /// Pings or responses handed to the stack
+    pub sent: u32,
+    /// Pings or responses received from the peer
+    pub received: u32,
+    /// Sends that failed plus gaps detected in the peer's sequence numbers
+    pub lost: u32,
+}
+
+impl PacketCounters {
+    /// `sent: u32`, `received: u32`, `lost: u32`
+    pub const LEN: usize = 12;
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut out = [0; Self::LEN];
+        out[0..4].copy_from_slice(&self.sent.to_le_bytes());
+        out[4..8].copy_from_slice(&self.received.to_le_bytes());
+        out[8..12].copy_from_slice(&self.lost.to_le_bytes());
+        out
+    }
+}
+
+/// Link-layer quality over one window of controller connection event reports
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkQuality {
+    pub window: Duration,
+    /// Connection events that took place
+    pub events: u16,
+    /// Advance of the connection event counter, which also counts the events
+    /// skipped for subrating or peripheral latency
+    pub event_span: u16,
+    /// Events in which the peer answered, i.e. at least one packet pair was exchanged
+    pub events_with_rx: u16,
+    pub crc_errors: u16,
+    /// Packets the peer did not acknowledge
+    pub naks: u16,
+}
+
+impl LinkQuality {
+    /// `window_ms: u16`, `events: u16`, `event_span: u16`, `events_with_rx: u16`,
+    /// `crc_errors: u16`, `naks: u16`
+    pub const LEN: usize = 12;
+
+    /// Connection interval implied by the event counter
+    pub fn interval(&self) -> Option<Duration> {
+        (self.event_span > 0).then(|| self.window / self.event_span as u32)
+    }
+
+    /// Intervals per event that took place, in hundredths: 100 without subrating or latency
+    pub fn effective_subrate_x100(&self) -> u32 {
+        (self.event_span as u32 * 100)
+            .checked_div(self.events as u32)
+            .unwrap_or(0)
+    }
+
+    /// Effective subrate rounded to the nearest factor
+    pub fn subrate_factor(&self) -> u16 {
+        ((self.effective_subrate_x100() + 50) / 100) as u16
+    }
+
+    pub fn rx_timeouts(&self) -> u16 {
+        self.events - self.events_with_rx
+    }
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut out = [0; Self::LEN];
+        let fields = [
+            self.window.as_millis() as u16,
+            self.events,
+            self.event_span,
+            self.events_with_rx,
+            self.crc_errors,
+            self.naks,
+        ];
+        for (chunk, value) in out.as_chunks_mut::<2>().0.iter_mut().zip(fields) {
+            *chunk = value.to_le_bytes();
+        }
+        out
+    }
+}
+
+/// Connection events on one data channel
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChannelCounts {
+    pub events: u16,
+    /// Events with a CRC error or without an answer from the peer
+    pub failed: u16,
+    pub crc_errors: u16,
+}
+
+impl ChannelCounts {
+    /// Share of failed events in tenths of a percent
+    pub fn error_rate_permille(&self) -> u32 {
+        (self.failed as u32 * 1000)
+            .checked_div(self.events as u32)
+            .unwrap_or(0)
+    }
+}
+
+/// Per-channel outcome of the connection events of one connection
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelErrors {
+    pub channels: [ChannelCounts; ChannelMask::COUNT as usize],
+}
+
+impl ChannelErrors {
+    pub const fn new() -> Self {
+        Self {
+            channels: [ChannelCounts {
+                events: 0,
+                failed: 0,
+                crc_errors: 0,
+            }; ChannelMask::COUNT as usize],
+        }
+    }
+
+    pub fn record(&mut self, channel: u8, crc_errors: u16, rx_timeout: bool) {
+        let Some(counts) = self.channels.get_mut(channel as usize) else {
+            return;
+        };
+        counts.events = counts.events.saturating_add(1);
+        if crc_errors > 0 || rx_timeout {
+            counts.failed = counts.failed.saturating_add(1);
+        }
+        counts.crc_errors = counts.crc_errors.saturating_add(crc_errors);
+    }
+
+    /// Channels that carried at least one event, with their counts
+    pub fn used(&self) -> impl Iterator<Item = (u8, &ChannelCounts)> {
+        (0..).zip(&self.channels).filter(|(_, c)| c.events > 0)
+    }
+}
+
+impl Default for ChannelErrors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// RSSI reported by the controller when no value is available
+const RSSI_UNAVAILABLE: i8 = 127;
+
+/// TX power and RSSI samples over one stats window
+#[derive(Debug, Clone, Copy)]
+pub struct Signal {
+    /// Level the controller selected for this connection, `None` if never set
+    pub tx_power_dbm: Option<i8>,
+    pub rssi_samples: u16,
+    pub rssi_last: i8,
+    pub rssi_min: i8,
+    pub rssi_max: i8,
+    rssi_sum: i32,
+}
+
+impl Signal {
+    /// `tx_power_dbm: i8` (127 if unknown), `rssi_samples: u16`, `rssi_last: i8`,
+    /// `rssi_min: i8`, `rssi_max: i8`, `rssi_mean: i8`, all RSSI values in dBm
+    pub const LEN: usize = 7;
+
+    pub const fn new() -> Self {
+        Self {
+            tx_power_dbm: None,
+            rssi_samples: 0,
+            rssi_last: RSSI_UNAVAILABLE,
+            rssi_min: i8::MAX,
+            rssi_max: i8::MIN,
+            rssi_sum: 0,
+        }
+    }
+
+    pub fn record_rssi(&mut self, rssi: i8) {
+        if rssi == RSSI_UNAVAILABLE {
+            return;
+        }
+        self.rssi_samples = self.rssi_samples.saturating_add(1);
+        self.rssi_last = rssi;
+        self.rssi_min = self.rssi_min.min(rssi);
+        self.rssi_max = self.rssi_max.max(rssi);
+        self.rssi_sum += rssi as i32;
+    }
+
+    pub fn rssi_mean(&self) -> Option<i8> {
+        (self.rssi_samples > 0).then(|| (self.rssi_sum / self.rssi_samples as i32) as i8)
+    }
+
+    /// Start a new window of RSSI samples, keeping the TX power
+    pub fn start_window(&mut self) {
+        *self = Self {
+            tx_power_dbm: self.tx_power_dbm,
+            ..Self::new()
+        };
+    }
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut out = [RSSI_UNAVAILABLE as u8; Self::LEN];
+        out[0] = self.tx_power_dbm.unwrap_or(RSSI_UNAVAILABLE) as u8;
+        out[1..3].copy_from_slice(&self.rssi_samples.to_le_bytes());
+        if self.rssi_samples > 0 {
+            out[3] = self.rssi_last as u8;
+            out[4] = self.rssi_min as u8;
+            out[5] = self.rssi_max as u8;
+            out[6] = self.rssi_mean().unwrap_or(RSSI_UNAVAILABLE) as u8;
+        }
+        out
+    }
+}
+
+impl Default for Signal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Statistics collected over one connection
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub link: LinkParams,
+    /// Round trips initiated by this side: own send until the peer's answer arrives
+    pub rtt: LatencyHistogram,
+    pub packets: PacketCounters,
+    pub signal: Signal,
+}
+
+impl Stats {
+    pub const fn new() -> Self {
+        Self {
+            link: LinkParams::new(),
+            rtt: LatencyHistogram::new(),
+            packets: PacketCounters {
+                sent: 0,
+                received: 0,
+                lost: 0,
+            },
+            signal: Signal::new(),
+        }
+    }
+
+    /// Clear the measurements, keeping the link parameters and TX power
+    pub fn reset(&mut self) {
+        self.rtt.reset();
+        self.packets = PacketCounters::default();
+        self.signal.start_window();
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn histogram(samples_us: &[u64]) -> LatencyHistogram {
+        let mut h = LatencyHistogram::new();
+        for &us in samples_us {
+            h.record(Duration::from_micros(us));
+        }
+        h
+    }
+
+    #[test]
+    fn percentiles_are_bucket_upper_bounds() {
+        // 98 in [512, 1024), 2 in [4096, 8192)
+        let mut samples = [600; 100];
+        samples[98..].fill(5000);
+        let s = histogram(&samples).summary();
+        assert_eq!(s.count, 100);
+        assert_eq!((s.min_us, s.max_us), (600, 5000));
+        assert_eq!(s.p50_us, 1023);
+        // Capped at the maximum seen rather than 8191
+        assert_eq!(s.p99_us, 5000);
+
+        let s = histogram(&[0, 1, 2, 3]).summary();
+        assert_eq!((s.p50_us, s.p99_us), (1, 3));
+    }
+
+    #[test]
+    fn last_bucket_collects_the_overflow() {
+        let s = histogram(&[40_000, 1_000_000]).summary();
+        assert_eq!(s.p50_us, 1_000_000);
+        assert_eq!(s.mean_us, 520_000);
+    }
+
+    #[test]
+    fn empty_histogram_summarizes_to_zero() {
+        let s = LatencyHistogram::new().summary();
+        assert_eq!((s.count, s.min_us, s.max_us, s.p99_us), (0, 0, 0, 0));
+    }
+
+    #[test]
+    fn link_params_layout() {
+        let link = LinkParams {
+            interval: Duration::from_micros(7500),
+            peripheral_latency: 3,
+            subrate_factor: 4,
+            supervision_timeout: Duration::from_millis(4000),
+            tx_phy: 2,
+            rx_phy: 1,
+        };
+        assert_eq!(
+            link.encode(),
+            [0x4c, 0x1d, 0, 0, 3, 0, 4, 0, 0xa0, 0x0f, 2, 1]
+        );
+        assert_eq!(LinkParams::default().subrate_factor, 1);
+        assert_eq!(Stats::default().link.subrate_factor, 1);
+    }
+
+    #[test]
+    fn summary_and_counter_layout() {
+        let summary = LatencySummary {
+            count: 1,
+            min_us: 2,
+            max_us: 3,
+            mean_us: 4,
+            p50_us: 5,
+            p99_us: 0x0102_0304,
+        };
+        let out = summary.encode();
+        assert_eq!(out[..8], [1, 0, 0, 0, 2, 0, 0, 0]);
+        assert_eq!(out[20..], [4, 3, 2, 1]);
+
+        let packets = PacketCounters {
+            sent: 10,
+            received: 9,
+            lost: 0x100,
+        };
+        assert_eq!(packets.encode(), [10, 0, 0, 0, 9, 0, 0, 0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn signal_layout() {
+        let mut signal = Signal::new();
+        assert_eq!(signal.encode(), [127, 0, 0, 127, 127, 127, 127]);
+
+        signal.tx_power_dbm = Some(-4);
+        signal.record_rssi(-60);
+        signal.record_rssi(-70);
+        // Unavailable samples don't count
+        signal.record_rssi(127);
+        assert_eq!(
+            signal.encode(),
+            [
+                -4i8 as u8,
+                2,
+                0,
+                -70i8 as u8,
+                -70i8 as u8,
+                -60i8 as u8,
+                -65i8 as u8
+            ]
+        );
+    }
+
+    #[test]
+    fn link_quality_layout() {
+        let quality = LinkQuality {
+            window: Duration::from_millis(1000),
+            events: 25,
+            event_span: 100,
+            events_with_rx: 24,
+            crc_errors: 1,
+            naks: 2,
+        };
+        assert_eq!(quality.subrate_factor(), 4);
+        assert_eq!(quality.rx_timeouts(), 1);
+        assert_eq!(
+            quality.encode(),
+            [0xe8, 0x03, 25, 0, 100, 0, 24, 0, 1, 0, 2, 0]
+        );
+    }
+}
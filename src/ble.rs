@@ -1,32 +1,57 @@
 #![allow(unused)]
+use bt_hci::AsHciBytes;
 use bt_hci::{
-    AsHciBytes,
     cmd::{
         info::ReadLocalSupportedCmds,
         le::{
-            LeConnectionRateRequest, LeFrameSpaceUpdate, LeReadLocalSupportedFeatures,
-            LeReadMinimumSupportedConnectionInterval, LeSetDefaultRateParameters, LeSetHostFeature,
-            LeSetPhy,
+            LeConnectionRateRequest, LeExtCreateConn, LeFrameSpaceUpdate, LeReadChannelMap,
+            LeReadLocalSupportedFeatures, LeReadMinimumSupportedConnectionInterval,
+            LeSetDefaultRateParameters, LeSetDefaultSubrate, LeSetHostChannelClassification,
+            LeSetHostFeature, LeSetPhy, LeSubrateRequest,
         },
+        status::ReadRssi,
     },
     controller::{ControllerCmdAsync, ControllerCmdSync},
 };
 
-#[cfg(feature = "peripheral")]
-use crate::gatt::CounterServer;
-use embassy_futures::{
-    join::join,
-    select::{Either, select},
-};
-use embassy_time::{Duration, Instant, Timer, with_timeout};
-use log::{info, warn};
+use common::rate::RateParams;
+use embassy_futures::select::{Either, select};
+use embassy_time::{Duration, Timer, with_timeout};
+use nrf_sdc::vendor::{QosConnEventReportEnable, ZephyrWriteTxPower};
 use static_cell::StaticCell;
-use trouble_host::gatt::GattConnectionEvent;
 use trouble_host::prelude::*;
 
+use crate::config::TEST_CONFIG;
+use crate::intervals::{self, SupportedIntervals};
+use crate::lfclk;
+use crate::watchdog::{self, Task};
+
+#[cfg(feature = "central")]
+mod adaptive;
+#[cfg(feature = "central")]
+mod bringup;
+#[cfg(feature = "central")]
+mod burst;
+#[cfg(feature = "central")]
+mod central;
+#[cfg(feature = "central")]
+mod channels;
+#[cfg(feature = "central")]
+mod discovery;
+#[cfg(feature = "central")]
+mod ladder;
+#[cfg(feature = "peripheral")]
+mod peripheral;
+#[cfg(feature = "peripheral")]
+mod policy;
+mod power;
+mod qos;
+#[cfg(feature = "central")]
+mod wakeup;
+
 const ADVERTISE_NAME: &str = "BLE-SCI-TEST";
 
-const CONNECTIONS_MAX: usize = 1;
+pub(crate) const CONNECTIONS_MAX: usize = 1;
 const L2CAP_CHANNELS_MAX: usize = 3;
 
 const SERVICE_UUID_BYTES: [u8; 16] = [
@@ -45,6 +70,10 @@ const CHAR_CMD_UUID: Uuid = Uuid::Uuid128([
     0xfb, 0x34, 0x9b, 0x5f, 0x80, 0x00, 0x00, 0x80, 0x00, 0x10, 0x00, 0x00, 0xe2, 0xff, 0x00, 0x00,
 ]);
 
+const CHAR_CONTROL_POINT_UUID: Uuid = Uuid::Uuid128([
+    0xfb, 0x34, 0x9b, 0x5f, 0x80, 0x00, 0x00, 0x80, 0x00, 0x10, 0x00, 0x00, 0xe3, 0xff, 0x00, 0x00,
+]);
+
 const PERIPHERAL_ADDR_BYTES: [u8; 6] = [0xff, 0x1f, 0x1f, 0x1f, 0x1f, 0xc0];
 
 /// Connection rate parameters for both central and peripheral
@@ -60,18 +89,99 @@ const CONN_RATE_PARAMS: ConnectRateParams = ConnectRateParams {
     min_ce_length: Duration::from_micros(500),
     max_ce_length: Duration::from_micros(500),
 };
+/// [`RateParams`] as the host stack takes them
+fn connect_rate_params(p: &RateParams) -> ConnectRateParams {
+    ConnectRateParams {
+        min_connection_interval: p.min_connection_interval,
+        max_connection_interval: p.max_connection_interval,
+        subrate_min: p.subrate_min,
+        subrate_max: p.subrate_max,
+        max_latency: p.max_latency,
+        continuation_number: p.continuation_number,
+        supervision_timeout: p.supervision_timeout,
+        min_ce_length: p.min_ce_length,
+        max_ce_length: p.max_ce_length,
+    }
+}
+
+/// The host stack's parameters for the control point and the link models
+fn rate_params(p: &ConnectRateParams) -> RateParams {
+    RateParams {
+        min_connection_interval: p.min_connection_interval,
+        max_connection_interval: p.max_connection_interval,
+        subrate_min: p.subrate_min,
+        subrate_max: p.subrate_max,
+        max_latency: p.max_latency,
+        continuation_number: p.continuation_number,
+        supervision_timeout: p.supervision_timeout,
+        min_ce_length: p.min_ce_length,
+        max_ce_length: p.max_ce_length,
+    }
+}
+
 const CENTRAL_ADDR_BYTES: [u8; 6] = [0xaa, 0x2f, 0x2f, 0x2f, 0x2f, 0xc0];
 
+/// Set in counter notifications the peripheral sends on its own in
+/// [`TestMode::PeripheralLatency`](crate::config::TestMode::PeripheralLatency)
+const ORIGINATED_FLAG: u32 = 1 << 31;
+
+/// Set in the command byte the central writes to echo an originated notification
+const ECHO_FLAG: u8 = 0x80;
+
+/// How often the host runner is probed with an HCI command for the watchdog
+const RUNNER_PROBE_PERIOD: Duration = Duration::from_secs(2);
+const RUNNER_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
 static RESOURCES: StaticCell<
     HostResources<DefaultPacketPool, CONNECTIONS_MAX, L2CAP_CHANNELS_MAX>,
 > = StaticCell::new();
 
-#[cfg(feature = "peripheral")]
-static SERVER: StaticCell<CounterServer<'static>> = StaticCell::new();
-
 #[cfg(all(feature = "peripheral", feature = "central"))]
 compile_error!("enable only one of the features: `peripheral` or `central`");
 
+/// HCI commands the test issues on top of the base [`Controller`]
+pub trait SciController:
+    Controller
+    + ControllerCmdSync<LeReadLocalSupportedFeatures>
+    + ControllerCmdSync<LeReadMinimumSupportedConnectionInterval>
+    + ControllerCmdSync<LeConnectionRateRequest>
+    + ControllerCmdSync<ReadLocalSupportedCmds>
+    + ControllerCmdAsync<LeSetPhy>
+    + ControllerCmdSync<LeFrameSpaceUpdate>
+    + ControllerCmdSync<LeSetDefaultRateParameters>
+    + ControllerCmdSync<LeSetHostFeature>
+    + ControllerCmdSync<QosConnEventReportEnable>
+    + ControllerCmdSync<ReadRssi>
+    + ControllerCmdSync<ZephyrWriteTxPower>
+    + ControllerCmdSync<LeSetHostChannelClassification>
+    + ControllerCmdSync<LeReadChannelMap>
+    + ControllerCmdAsync<LeExtCreateConn>
+    + ControllerCmdSync<LeSetDefaultSubrate>
+    + ControllerCmdAsync<LeSubrateRequest>
+{
+}
+
+impl<C> SciController for C where
+    C: Controller
+        + ControllerCmdSync<LeReadLocalSupportedFeatures>
+        + ControllerCmdSync<LeReadMinimumSupportedConnectionInterval>
+        + ControllerCmdSync<LeConnectionRateRequest>
+        + ControllerCmdSync<ReadLocalSupportedCmds>
+        + ControllerCmdAsync<LeSetPhy>
+        + ControllerCmdSync<LeFrameSpaceUpdate>
+        + ControllerCmdSync<LeSetDefaultRateParameters>
+        + ControllerCmdSync<LeSetHostFeature>
+        + ControllerCmdSync<QosConnEventReportEnable>
+        + ControllerCmdSync<ReadRssi>
+        + ControllerCmdSync<ZephyrWriteTxPower>
+        + ControllerCmdSync<LeSetHostChannelClassification>
+        + ControllerCmdSync<LeReadChannelMap>
+        + ControllerCmdAsync<LeExtCreateConn>
+        + ControllerCmdSync<LeSetDefaultSubrate>
+        + ControllerCmdAsync<LeSubrateRequest>
+{
+}
+
 /// Set host feature bits for Connection Subrating and Shorter Connection Intervals
 async fn set_host_features<C, P>(stack: &Stack<'_, C, P>)
 where
@@ -95,18 +205,69 @@ where
     }
 }
 
-pub async fn run<C>(controller: C)
+/// Read, log and remember the connection intervals the controller supports
+async fn read_supported_intervals<C, P>(stack: &Stack<'_, C, P>)
 where
-    C: Controller
-        + ControllerCmdSync<LeReadLocalSupportedFeatures>
-        + ControllerCmdSync<LeReadMinimumSupportedConnectionInterval>
-        + ControllerCmdSync<LeConnectionRateRequest>
-        + ControllerCmdSync<ReadLocalSupportedCmds>
-        + ControllerCmdAsync<LeSetPhy>
-        + ControllerCmdSync<LeFrameSpaceUpdate>
-        + ControllerCmdSync<LeSetDefaultRateParameters>
-        + ControllerCmdSync<LeSetHostFeature>,
+    C: Controller + ControllerCmdSync<LeReadMinimumSupportedConnectionInterval>,
+    P: trouble_host::PacketPool,
 {
+    match stack
+        .command(LeReadMinimumSupportedConnectionInterval::new())
+        .await
+    {
+        Ok(res) => {
+            // The raw return parameters, as the host tests in `common` take them
+            debug!("Supported intervals response: {:?}", res.as_hci_bytes());
+            match SupportedIntervals::decode(res.as_hci_bytes()) {
+                Ok(supported) => {
+                    supported.log();
+                    intervals::set_supported(supported);
+                }
+                Err(e) => warn!("Failed to decode supported connection intervals: {}", e),
+            }
+        }
+        Err(e) => warn!(
+            "Failed to read minimum supported connection interval: {:?}",
+            e
+        ),
+    }
+}
+
+/// Drive the host runner with the QoS report handler, checking in with the watchdog while it still completes HCI commands
+async fn run_runner<C: SciController>(
+    runner: &mut Runner<'_, C, DefaultPacketPool>,
+    stack: &Stack<'_, C, DefaultPacketPool>,
+) {
+    let probe = async {
+        loop {
+            // Command completions are delivered by the runner, so this stalls with it
+            let features = stack.command(LeReadLocalSupportedFeatures::new());
+            if let Ok(Ok(_)) = with_timeout(RUNNER_PROBE_TIMEOUT, features).await {
+                watchdog::check_in(Task::HostRunner);
+            }
+            Timer::after(RUNNER_PROBE_PERIOD).await;
+        }
+    };
+
+    if let Either::First(Err(e)) = select(runner.run_with_handler(&qos::QOS), probe).await {
+        warn!("Host runner stopped: {:?}", e);
+    }
+}
+
+/// Settings that shape the measurements, logged once per boot
+fn log_session_header() {
+    let clock = lfclk::active();
+    info!(
+        "Session: {:?} with {:?}, LF clock {:?} at {} ppm",
+        TEST_CONFIG.mode, TEST_CONFIG.ping, clock.source, clock.accuracy_ppm
+    );
+    info!("Rate control: {:?}", TEST_CONFIG.rate);
+    if let Some((run, runs)) = lfclk::comparison_run() {
+        info!("LF clock comparison run {}/{}", run, runs);
+    }
+}
+
+pub async fn run<C: SciController>(controller: C) {
     let address = Address::random([0, 0, 0, 0, 0, 0]);
 
     #[cfg(feature = "peripheral")]
@@ -116,298 +277,14 @@ where
     let address = Address::random(CENTRAL_ADDR_BYTES);
 
     info!("Starting BLE Stack with address {:?}", address);
+    log_session_header();
 
     let resources = RESOURCES.init(HostResources::new());
     let stack = trouble_host::new(controller, resources).set_random_address(address);
 
     #[cfg(feature = "peripheral")]
-    {
-        let Host {
-            mut peripheral,
-            mut runner,
-            ..
-        } = stack.build();
-
-        let server = SERVER.init(
-            CounterServer::new_with_config(GapConfig::Peripheral(PeripheralConfig {
-                name: ADVERTISE_NAME,
-                appearance: &appearance::power_device::GENERIC_POWER_DEVICE,
-            }))
-            .unwrap(),
-        );
-
-        join(runner.run(), async {
-            // Enable host features for Connection Subrating and Shorter Connection Intervals
-            set_host_features(&stack).await;
-
-            let mut adv_data = [0; 31];
-            let mut scan_data = [0; 31];
-
-            let len_adv = AdStructure::encode_slice(
-                &[
-                    AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
-                    AdStructure::ServiceUuids128(&[SERVICE_UUID_BYTES]),
-                ],
-                &mut adv_data,
-            )
-            .unwrap();
-
-            let len_scan = AdStructure::encode_slice(
-                &[AdStructure::CompleteLocalName(ADVERTISE_NAME.as_bytes())],
-                &mut scan_data,
-            )
-            .unwrap();
-
-            loop {
-                info!("Advertising...");
-
-                let advertiser = peripheral
-                    .advertise(
-                        &Default::default(),
-                        Advertisement::ConnectableScannableUndirected {
-                            adv_data: &adv_data[..len_adv],
-                            scan_data: &scan_data[..len_scan],
-                        },
-                    )
-                    .await
-                    .unwrap();
-
-                let connection = match advertiser.accept().await {
-                    Ok(conn) => conn,
-                    Err(_) => continue,
-                };
-
-                let mut counter: u32 = 0;
-                let gatt_conn = connection.with_attribute_server(server).unwrap();
-
-                loop {
-                    let event = gatt_conn.next().await;
-
-                    match event {
-                        GattConnectionEvent::Disconnected { .. } => break,
-                        GattConnectionEvent::Gatt { event } => {
-                            if let GattEvent::Write { .. } = event {
-                                server
-                                    .counter_service
-                                    .counter
-                                    .set(&server, &counter)
-                                    .unwrap();
-                                let _ = server
-                                    .counter_service
-                                    .counter
-                                    .notify(&gatt_conn, &counter)
-                                    .await;
-                                counter = counter.wrapping_add(1);
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-            }
-        })
-        .await;
-    }
+    peripheral::run(&stack).await;
 
     #[cfg(feature = "central")]
-    {
-        let Host {
-            mut central,
-            mut runner,
-            ..
-        } = stack.build();
-        let target = Address::random(PERIPHERAL_ADDR_BYTES);
-
-        let config = ConnectConfig {
-            connect_params: Default::default(),
-            scan_config: ScanConfig {
-                filter_accept_list: &[(target.kind, &target.addr)],
-                ..Default::default()
-            },
-        };
-
-        join(runner.run(), async {
-            // Enable host features for Connection Subrating and Shorter Connection Intervals
-            set_host_features(&stack).await;
-
-            loop {
-                info!("Connecting to {:?}...", target);
-                match central.connect(&config).await {
-                    Ok(conn) => {
-                        use bt_hci::{AsHciBytes, param::SpacingTypes};
-
-                        match stack.command(LeReadLocalSupportedFeatures::new()).await {
-                            Ok(supported) => {
-                                info!("supported features: {:?}", supported.as_hci_bytes())
-                            }
-                            Err(e) => warn!("Failed to read supported features: {:?}", e),
-                        }
-
-                        let connection_params = RequestedConnParams {
-                            min_connection_interval: Duration::from_micros(7500),
-                            max_connection_interval: Duration::from_micros(7500),
-                            max_latency: 0,
-                            min_event_length: Duration::from_micros(0),
-                            max_event_length: Duration::from_micros(0),
-                            supervision_timeout: Duration::from_millis(500),
-                        };
-
-                        match conn.set_phy(&stack, PhyKind::Le2M).await {
-                            Ok(_) => info!("PHY set to LE 2M"),
-                            Err(e) => warn!("Failed to set PHY: {:?}", e),
-                        }
-
-                        match conn
-                            .update_connection_params(&stack, &connection_params)
-                            .await
-                        {
-                            Ok(_) => info!("Connection parameters updated to 7.5ms"),
-                            Err(e) => warn!("Failed to update connection parameters: {:?}", e),
-                        }
-
-                        match conn
-                            .update_frame_space(
-                                &stack,
-                                Duration::from_micros(0),
-                                Duration::from_micros(125),
-                                PhyMask::new().set_le_2m_phy(true),
-                                SpacingTypes::new()
-                                    .set_t_ifs_acl_cp(true)
-                                    .set_t_ifs_acl_pc(true)
-                                    .set_t_mces(true),
-                            )
-                            .await
-                        {
-                            Ok(_) => info!("Frame space updated"),
-                            Err(e) => warn!("Failed to update frame space: {:?}", e),
-                        }
-
-                        match stack.command(ReadLocalSupportedCmds::new()).await {
-                            Ok(res) => info!("LE command mask: {:?}", res.as_hci_bytes()[48]),
-                            Err(e) => warn!("Failed to read local supported commands: {:?}", e),
-                        }
-
-                        match stack.read_minimum_supported_connection_interval().await {
-                            Ok(res) => info!(
-                                "Minimum supported connection interval: {:?}us",
-                                res.minimum_supported_connection_interval.as_micros()
-                            ),
-                            Err(e) => warn!(
-                                "Failed to read minimum supported connection interval: {:?}",
-                                e
-                            ),
-                        }
-
-
-                        Timer::after(Duration::from_millis(500)).await;
-                        info!(
-                            "Requesting connection rate: interval={}us (N={}), subrate={}-{}, latency={}, cont={}, ce={}-{}us",
-                            CONN_RATE_PARAMS.min_connection_interval.as_micros(),
-                            CONN_RATE_PARAMS.min_connection_interval.as_micros() / 125,
-                            CONN_RATE_PARAMS.subrate_min,
-                            CONN_RATE_PARAMS.subrate_max,
-                            CONN_RATE_PARAMS.max_latency,
-                            CONN_RATE_PARAMS.continuation_number,
-                            CONN_RATE_PARAMS.min_ce_length.as_micros(),
-                            CONN_RATE_PARAMS.max_ce_length.as_micros()
-                        );
-
-                        const MAX_RETRIES: u32 = 10;
-                        for i in 0..MAX_RETRIES  {
-                            match conn
-                                .request_connection_rate(&stack, &CONN_RATE_PARAMS)
-                                .await
-                            {
-                                Ok(_) => {
-                                    info!("Connection rate request sent successfully");
-                                    break;
-                                }
-                                Err(e) => {
-                                    warn!(
-                                        "Connection rate request failed (retry {}/{}): {:?}",
-                                        i, MAX_RETRIES, e
-                                    );
-                                    Timer::after(Duration::from_millis(200)).await;
-                                }
-                            }
-                        }
-
-                        let client = match GattClient::<_, DefaultPacketPool, 10>::new(
-                            &stack, &conn,
-                        )
-                        .await
-                        {
-                            Ok(c) => c,
-                            Err(e) => {
-                                warn!("Failed to create GATT client: {:?}", e);
-                                continue;
-                            }
-                        };
-
-                        let _ = join(client.task(), async {
-                            let (counter_char, command_char) = loop {
-                                if let Ok(services) = client.services_by_uuid(&SERVICE_UUID).await {
-                                    if let Some(service) = services.first() {
-                                        let c = client
-                                            .characteristic_by_uuid::<u32>(service, &CHAR_UUID)
-                                            .await;
-                                        let cmd = client
-                                            .characteristic_by_uuid::<u8>(service, &CHAR_CMD_UUID)
-                                            .await;
-                                        if let (Ok(c), Ok(cmd)) = (c, cmd) {
-                                            break (c, cmd);
-                                        }
-                                    }
-                                }
-                                Timer::after(Duration::from_millis(500)).await;
-                            };
-
-                            let mut listener = match client.subscribe(&counter_char, false).await {
-                                Ok(l) => l,
-                                Err(e) => {
-                                    warn!("Failed to subscribe: {:?}", e);
-                                    return;
-                                }
-                            };
-
-                            info!("Subscribed. Starting Ping-Pong.");
-                            if let Err(e) = client.write_characteristic(&command_char, &[1u8]).await
-                            {
-                                warn!("Failed to send initial ping: {:?}", e);
-                            }
-
-                            let mut counter: u32 = 0;
-                            let mut last_tick: Option<Instant> = None;
-
-                            loop {
-                                let _ = listener.next().await;
-
-                                // Track timing like peripheral does
-                            let now = Instant::now();
-                            if let Some(prev) = last_tick {
-                                let elapsed = now - prev;
-                                if counter % 100 == 0 {
-                                    let ms = elapsed.as_micros() as f64 / 2000.0;
-                                    info!("Client Count: {} | Interval: {:.3}ms", counter, ms);
-                                }
-                            }
-                            last_tick = Some(now);
-                                counter = counter.wrapping_add(1);
-
-                                if let Err(e) =
-                                    client.write_characteristic(&command_char, &[1u8]).await
-                                {
-                                    warn!("Ping-pong broken: {:?}", e);
-                                    break;
-                                }
-                            }
-                        })
-                        .await;
-                    }
-                    Err(e) => warn!("Connect failed: {:?}", e),
-                }
-                Timer::after(Duration::from_secs(2)).await;
-            }
-        })
-        .await;
-    }
+    central::run(&stack).await;
 }
@@ -0,0 +1,223 @@
+//! Traffic-driven switching between a low-latency and a low-power rate.
+//!
+//! [`AdaptivePolicy`] watches the central's exchanges. After `idle_ms` without
+//! one it subrates the link to the low-power profile, and once
+//! `activity_exchanges` fall within `activity_window_ms` it requests the target
+//! rate again. Either profile is held for at least `min_dwell_ms`. A switch is
+//! done once the QoS reports show the profile's event gap, which times the
+//! switch. Exchanges that start in low power, or before the link is back to
+//! low latency, count as delayed.
+
+use embassy_time::{Duration, Instant, Timer};
+use trouble_host::prelude::*;
+
+use super::qos::QOS;
+use super::{CONN_RATE_PARAMS, SciController};
+use crate::config::AdaptiveConfig;
+use crate::stats::LatencyHistogram;
+
+/// Give up on seeing a requested profile in the QoS reports after this long
+const SWITCH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often the idle timer is checked while traffic pauses
+const IDLE_TICK: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Profile {
+    /// The target rate, an event every interval
+    LowLatency,
+    /// The target interval subrated, with peripheral latency
+    LowPower,
+}
+
+impl Profile {
+    fn params(self, config: &AdaptiveConfig) -> ConnectRateParams {
+        match self {
+            Profile::LowLatency => CONN_RATE_PARAMS,
+            Profile::LowPower => ConnectRateParams {
+                subrate_min: config.low_power_subrate,
+                subrate_max: config.low_power_subrate,
+                max_latency: config.low_power_latency,
+                continuation_number: config.low_power_continuation,
+                ..CONN_RATE_PARAMS
+            },
+        }
+    }
+
+    /// Intervals between the central's events once the profile is in effect
+    fn event_gap(self, config: &AdaptiveConfig) -> u16 {
+        match self {
+            Profile::LowLatency => CONN_RATE_PARAMS.subrate_min,
+            Profile::LowPower => config.low_power_subrate,
+        }
+    }
+}
+
+/// A requested profile the QoS reports don't show yet
+struct Switch {
+    to: Profile,
+    requested_at: Instant,
+}
+
+pub struct AdaptivePolicy {
+    config: AdaptiveConfig,
+    /// Last profile requested
+    profile: Profile,
+    switch: Option<Switch>,
+    dwell_until: Instant,
+    last_exchange: Instant,
+    window_started: Instant,
+    window_exchanges: u32,
+    exchanges: u32,
+    /// Exchanges that started outside the low-latency profile
+    delayed: u32,
+    to_low_power: LatencyHistogram,
+    to_low_latency: LatencyHistogram,
+    /// Switches never seen in the QoS reports
+    unconfirmed: u32,
+}
+
+impl AdaptivePolicy {
+    /// Start out in low latency, where the bring-up left the link
+    pub fn new(config: AdaptiveConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            config,
+            profile: Profile::LowLatency,
+            switch: None,
+            dwell_until: now,
+            last_exchange: now,
+            window_started: now,
+            window_exchanges: 0,
+            exchanges: 0,
+            delayed: 0,
+            to_low_power: LatencyHistogram::new(),
+            to_low_latency: LatencyHistogram::new(),
+            unconfirmed: 0,
+        }
+    }
+
+    /// Account for an exchange about to start
+    pub fn on_exchange(&mut self) {
+        let now = Instant::now();
+        self.exchanges += 1;
+        if self.profile == Profile::LowPower || self.switch.is_some() {
+            self.delayed += 1;
+        }
+
+        if now - self.window_started > ms(self.config.activity_window_ms) {
+            self.window_started = now;
+            self.window_exchanges = 0;
+        }
+        self.window_exchanges += 1;
+        self.last_exchange = now;
+    }
+
+    /// Time a pending switch and request the next profile if traffic calls for it
+    pub async fn update<C: SciController>(
+        &mut self,
+        stack: &Stack<'_, C, DefaultPacketPool>,
+        conn: &Connection<'_, DefaultPacketPool>,
+    ) {
+        self.check_switch();
+        if let Some(to) = self.decide() {
+            self.request(stack, conn, to).await;
+        }
+    }
+
+    /// Pause the traffic, dropping to low power once the idle timer expires
+    pub async fn idle<C: SciController>(
+        &mut self,
+        stack: &Stack<'_, C, DefaultPacketPool>,
+        conn: &Connection<'_, DefaultPacketPool>,
+        pause: Duration,
+    ) {
+        let until = Instant::now() + pause;
+        while Instant::now() < until {
+            Timer::at((Instant::now() + IDLE_TICK).min(until)).await;
+            self.update(stack, conn).await;
+        }
+    }
+
+    fn decide(&self) -> Option<Profile> {
+        let now = Instant::now();
+        if now < self.dwell_until {
+            return None;
+        }
+        match self.profile {
+            Profile::LowLatency if now - self.last_exchange >= ms(self.config.idle_ms) => {
+                Some(Profile::LowPower)
+            }
+            Profile::LowPower
+                if self.window_exchanges >= self.config.activity_exchanges
+                    && now - self.window_started <= ms(self.config.activity_window_ms) =>
+            {
+                Some(Profile::LowLatency)
+            }
+            _ => None,
+        }
+    }
+
+    async fn request<C: SciController>(
+        &mut self,
+        stack: &Stack<'_, C, DefaultPacketPool>,
+        conn: &Connection<'_, DefaultPacketPool>,
+        to: Profile,
+    ) {
+        let requested_at = Instant::now();
+        // Failed requests are retried no sooner than a switch would be
+        self.dwell_until = requested_at + ms(self.config.min_dwell_ms);
+        match conn
+            .request_connection_rate(stack, &to.params(&self.config))
+            .await
+        {
+            Ok(_) => {
+                self.profile = to;
+                self.switch = Some(Switch { to, requested_at });
+            }
+            Err(e) => warn!("Failed to request {:?} profile: {:?}", to, e),
+        }
+    }
+
+    fn check_switch(&mut self) {
+        let Some(switch) = &self.switch else {
+            return;
+        };
+        let target = switch.to.event_gap(&self.config);
+        match QOS.event_gap() {
+            Some((gap, since)) if gap == target && since >= switch.requested_at => {
+                let latency = since - switch.requested_at;
+                info!("Switched to {:?} in {}us", switch.to, latency.as_micros());
+                match switch.to {
+                    Profile::LowPower => self.to_low_power.record(latency),
+                    Profile::LowLatency => self.to_low_latency.record(latency),
+                }
+                self.switch = None;
+            }
+            _ if switch.requested_at.elapsed() > SWITCH_TIMEOUT => {
+                warn!("{:?} profile not seen in the QoS reports", switch.to);
+                self.unconfirmed += 1;
+                self.switch = None;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn log(&self) {
+        let down = self.to_low_power.summary();
+        let up = self.to_low_latency.summary();
+        info!(
+            "Adaptive policy: {:?}, {} of {} exchanges delayed, {} switches unconfirmed",
+            self.profile, self.delayed, self.exchanges, self.unconfirmed
+        );
+        info!(
+            "  to low power: n={} mean {}us max {}us, to low latency: n={} mean {}us max {}us",
+            down.count, down.mean_us, down.max_us, up.count, up.mean_us, up.max_us
+        );
+    }
+}
+
+fn ms(ms: u32) -> Duration {
+    Duration::from_millis(ms as u64)
+}
@@ -0,0 +1,456 @@
+//! Link bring-up after connecting, timed step by step.
+//!
+//! [`BringUp::Serial`] runs every procedure one after the other, with a fixed
+//! pause before the connection rate request and between its retries.
+//! [`BringUp::Overlapped`] skips the legacy parameter update the rate request
+//! supersedes, waits for the controller's completion events instead of
+//! sleeping, and runs alongside GATT setup. Both fill a [`Timeline`] so the
+//! time until the link is usable can be compared.
+//!
+//! With [`ConnectStrategy::Direct`] the link already starts close to the target
+//! rate, so the legacy parameter update is skipped in both pipelines.
+//! [`RateControl::Subrate`] subrates the 7.5 ms base interval instead of
+//! requesting a shorter one, so it always needs that base.
+
+use core::cell::RefCell;
+
+use bt_hci::cmd::info::ReadLocalSupportedCmds;
+use bt_hci::cmd::le::{
+    LeReadLocalSupportedFeatures, LeSetDefaultRateParameters, LeSetDefaultSubrate, LeSubrateRequest,
+};
+use bt_hci::param::Duration as HciDuration;
+use bt_hci::{AsHciBytes, param::SpacingTypes};
+use embassy_futures::join::join;
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use trouble_host::prelude::*;
+
+use super::{CONN_RATE_PARAMS, SciController, channels, ladder, power, read_supported_intervals};
+use crate::config::{BringUp, ConnectStrategy, RateControl, SubrateParams, TEST_CONFIG};
+use crate::intervals;
+use crate::watchdog::{self, Task};
+
+/// Pause before the rate request in the serial bring-up
+const SETTLE: Duration = Duration::from_millis(500);
+
+/// Pause between rate request retries in the serial bring-up
+pub(super) const RETRY_PAUSE: Duration = Duration::from_millis(200);
+
+const RATE_REQUEST_ATTEMPTS: u32 = 10;
+
+/// Longest wait for a link-layer procedure to complete
+const PROCEDURE_TIMEOUT: Duration = Duration::from_millis(500);
+
+const MARKS_MAX: usize = 11;
+
+/// Shortest interval create connection can carry, in its 1.25 ms units
+const CREATE_CONN_MIN_INTERVAL: Duration = Duration::from_micros(7500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Step {
+    /// Local reads of supported features, commands and the minimum interval
+    ControllerInfo,
+    PhyUpdate,
+    /// Legacy connection parameter update to the 7.5 ms base
+    ParamsUpdate,
+    FrameSpace,
+    /// Fixed pause before the rate request
+    Settle,
+    /// Connection rate or subrate request, for the rate until the controller reports an interval
+    RateRequest,
+    TxPower,
+    GattClient,
+    Discovery,
+    Subscribe,
+    PeerConfig,
+}
+
+#[derive(Clone, Copy)]
+struct Mark {
+    step: Step,
+    started: Duration,
+    finished: Duration,
+}
+
+/// When each bring-up step ran, relative to the connection
+pub struct Timeline {
+    connected_at: Instant,
+    marks: RefCell<[Option<Mark>; MARKS_MAX]>,
+}
+
+impl Timeline {
+    pub fn new() -> Self {
+        Self {
+            connected_at: Instant::now(),
+            marks: RefCell::new([None; MARKS_MAX]),
+        }
+    }
+
+    /// Run `step` and record when it started and finished
+    ///
+    /// Every step checks in for the test loop, which the bring-up runs in.
+    pub async fn time<F: Future>(&self, step: Step, step_fut: F) -> F::Output {
+        let started = self.connected_at.elapsed();
+        let output = step_fut.await;
+        watchdog::check_in(Task::TestLoop);
+        let mark = Mark {
+            step,
+            started,
+            finished: self.connected_at.elapsed(),
+        };
+        if let Some(slot) = self.marks.borrow_mut().iter_mut().find(|m| m.is_none()) {
+            *slot = Some(mark);
+        }
+        output
+    }
+
+    /// Log the steps in the order they started, the link counts as ready now
+    pub fn log(&self) {
+        let mut marks = *self.marks.borrow();
+        marks.sort_unstable_by_key(|m| (m.is_none(), m.map(|m| m.started)));
+
+        info!(
+            "Bring-up timeline ({:?}, {:?}):",
+            TEST_CONFIG.bring_up, TEST_CONFIG.connect
+        );
+        for m in marks.iter().flatten() {
+            info!(
+                "  {:?}: {}us to {}us ({}us)",
+                m.step,
+                m.started.as_micros(),
+                m.finished.as_micros(),
+                (m.finished - m.started).as_micros()
+            );
+        }
+        info!(
+            "Link ready {}ms after connecting",
+            self.connected_at.elapsed().as_millis()
+        );
+    }
+}
+
+/// Link-layer bring-up of the configured [`BringUp`], returns the selected TX power
+pub async fn set_up_link<C: SciController>(
+    stack: &Stack<'_, C, DefaultPacketPool>,
+    conn: &Connection<'_, DefaultPacketPool>,
+    timeline: &Timeline,
+) -> Option<i8> {
+    match TEST_CONFIG.bring_up {
+        BringUp::Serial => {
+            timeline
+                .time(Step::ControllerInfo, log_controller_info(stack))
+                .await;
+            timeline.time(Step::PhyUpdate, set_phy(stack, conn)).await;
+            if TEST_CONFIG.connect == ConnectStrategy::UpdateAfterConnect {
+                timeline
+                    .time(Step::ParamsUpdate, update_params(stack, conn))
+                    .await;
+            }
+            timeline
+                .time(Step::FrameSpace, update_frame_space(stack, conn))
+                .await;
+            timeline.time(Step::Settle, Timer::after(SETTLE)).await;
+            timeline
+                .time(Step::RateRequest, request_rate(stack, conn))
+                .await;
+        }
+        BringUp::Overlapped => {
+            // The controller runs one procedure per link at a time, so only the local reads overlap
+            let procedures = async {
+                if timeline.time(Step::PhyUpdate, set_phy(stack, conn)).await {
+                    wait_for(conn, |e| matches!(e, ConnectionEvent::PhyUpdated { .. })).await;
+                }
+                if needs_base_interval() {
+                    let update = async {
+                        update_params(stack, conn).await;
+                        wait_for(conn, |e| {
+                            matches!(e, ConnectionEvent::ConnectionParamsUpdated { .. })
+                        })
+                        .await
+                    };
+                    timeline.time(Step::ParamsUpdate, update).await;
+                }
+            };
+            join(
+                timeline.time(Step::ControllerInfo, log_controller_info(stack)),
+                procedures,
+            )
+            .await;
+            // The rate ladder steps through the interval table, so it waits for the read
+            // The host stack drops subrate change events, the QoS windows track them
+            timeline
+                .time(Step::RateRequest, request_rate(stack, conn))
+                .await;
+            timeline
+                .time(Step::FrameSpace, update_frame_space(stack, conn))
+                .await;
+        }
+    }
+
+    channels::log_channel_map(stack, conn).await;
+    timeline
+        .time(Step::TxPower, set_tx_power(stack, conn))
+        .await
+}
+
+/// Whether the overlapped bring-up has to move the link to the 7.5 ms base itself
+fn needs_base_interval() -> bool {
+    TEST_CONFIG.connect == ConnectStrategy::UpdateAfterConnect
+        && matches!(TEST_CONFIG.rate, RateControl::Subrate(_))
+}
+
+/// Make the target rate or subrate the controller's default for new links
+pub async fn set_default_rate<C: SciController>(stack: &Stack<'_, C, DefaultPacketPool>) {
+    if let RateControl::Subrate(p) = TEST_CONFIG.rate {
+        let command = LeSetDefaultSubrate::new(
+            p.subrate_min,
+            p.subrate_max,
+            p.max_latency,
+            p.continuation_number,
+            HciDuration::from_millis(p.supervision_timeout_ms as u32),
+        );
+        match stack.command(command).await {
+            Ok(_) => info!(
+                "Default subrate set: {}-{}, latency={}, cont={}",
+                p.subrate_min, p.subrate_max, p.max_latency, p.continuation_number
+            ),
+            Err(e) => warn!("Failed to set default subrate: {:?}", e),
+        }
+        return;
+    }
+
+    let p = &CONN_RATE_PARAMS;
+    let command = LeSetDefaultRateParameters::new(
+        HciDuration::from_micros(p.min_connection_interval.as_micros()),
+        HciDuration::from_micros(p.max_connection_interval.as_micros()),
+        p.subrate_min,
+        p.subrate_max,
+        p.max_latency,
+        p.continuation_number,
+        HciDuration::from_micros(p.supervision_timeout.as_micros()),
+        HciDuration::from_micros(p.min_ce_length.as_micros()),
+        HciDuration::from_micros(p.max_ce_length.as_micros()),
+    );
+    match stack.command(command).await {
+        Ok(_) => info!(
+            "Default rate parameters set: interval={}us, subrate={}-{}",
+            p.min_connection_interval.as_micros(),
+            p.subrate_min,
+            p.subrate_max
+        ),
+        Err(e) => warn!("Failed to set default rate parameters: {:?}", e),
+    }
+}
+
+/// Parameters to create the connection with, as close to [`CONN_RATE_PARAMS`] as it can carry
+pub fn initial_conn_params() -> RequestedConnParams {
+    match TEST_CONFIG.connect {
+        ConnectStrategy::UpdateAfterConnect => Default::default(),
+        ConnectStrategy::Direct => RequestedConnParams {
+            min_connection_interval: CONN_RATE_PARAMS
+                .min_connection_interval
+                .max(CREATE_CONN_MIN_INTERVAL),
+            max_connection_interval: CONN_RATE_PARAMS
+                .max_connection_interval
+                .max(CREATE_CONN_MIN_INTERVAL),
+            max_latency: CONN_RATE_PARAMS.max_latency,
+            min_event_length: CONN_RATE_PARAMS.min_ce_length,
+            max_event_length: CONN_RATE_PARAMS.max_ce_length,
+            supervision_timeout: CONN_RATE_PARAMS.supervision_timeout,
+        },
+    }
+}
+
+async fn log_controller_info<C: SciController>(stack: &Stack<'_, C, DefaultPacketPool>) {
+    match stack.command(LeReadLocalSupportedFeatures::new()).await {
+        Ok(supported) => {
+            info!("supported features: {:?}", supported.as_hci_bytes())
+        }
+        Err(e) => warn!("Failed to read supported features: {:?}", e),
+    }
+
+    match stack.command(ReadLocalSupportedCmds::new()).await {
+        Ok(res) => info!("LE command mask: {:?}", res.as_hci_bytes()[48]),
+        Err(e) => warn!("Failed to read local supported commands: {:?}", e),
+    }
+
+    read_supported_intervals(stack).await;
+}
+
+async fn set_phy<C: SciController>(
+    stack: &Stack<'_, C, DefaultPacketPool>,
+    conn: &Connection<'_, DefaultPacketPool>,
+) -> bool {
+    match conn.set_phy(stack, PhyKind::Le2M).await {
+        Ok(_) => {
+            info!("PHY set to LE 2M");
+            true
+        }
+        Err(e) => {
+            warn!("Failed to set PHY: {:?}", e);
+            false
+        }
+    }
+}
+
+async fn update_params<C: SciController>(
+    stack: &Stack<'_, C, DefaultPacketPool>,
+    conn: &Connection<'_, DefaultPacketPool>,
+) {
+    let connection_params = RequestedConnParams {
+        min_connection_interval: Duration::from_micros(7500),
+        max_connection_interval: Duration::from_micros(7500),
+        max_latency: 0,
+        min_event_length: Duration::from_micros(0),
+        max_event_length: Duration::from_micros(0),
+        supervision_timeout: Duration::from_millis(500),
+    };
+
+    match conn
+        .update_connection_params(stack, &connection_params)
+        .await
+    {
+        Ok(_) => info!("Connection parameters updated to 7.5ms"),
+        Err(e) => warn!("Failed to update connection parameters: {:?}", e),
+    }
+}
+
+async fn update_frame_space<C: SciController>(
+    stack: &Stack<'_, C, DefaultPacketPool>,
+    conn: &Connection<'_, DefaultPacketPool>,
+) {
+    match conn
+        .update_frame_space(
+            stack,
+            Duration::from_micros(0),
+            Duration::from_micros(125),
+            PhyMask::new().set_le_2m_phy(true),
+            SpacingTypes::new()
+                .set_t_ifs_acl_cp(true)
+                .set_t_ifs_acl_pc(true)
+                .set_t_mces(true),
+        )
+        .await
+    {
+        Ok(_) => info!("Frame space updated"),
+        Err(e) => warn!("Failed to update frame space: {:?}", e),
+    }
+}
+
+/// Request the target rate or subrate, for the rate down the fallback ladder until one is applied
+async fn request_rate<C: SciController>(
+    stack: &Stack<'_, C, DefaultPacketPool>,
+    conn: &Connection<'_, DefaultPacketPool>,
+) -> bool {
+    if let RateControl::Subrate(p) = TEST_CONFIG.rate {
+        return request_subrate(stack, conn, &p).await;
+    }
+
+    info!(
+        "Requesting connection rate: interval={}us (N={}), subrate={}-{}, latency={}, cont={}, ce={}-{}us",
+        CONN_RATE_PARAMS.min_connection_interval.as_micros(),
+        CONN_RATE_PARAMS.min_connection_interval.as_micros() / 125,
+        CONN_RATE_PARAMS.subrate_min,
+        CONN_RATE_PARAMS.subrate_max,
+        CONN_RATE_PARAMS.max_latency,
+        CONN_RATE_PARAMS.continuation_number,
+        CONN_RATE_PARAMS.min_ce_length.as_micros(),
+        CONN_RATE_PARAMS.max_ce_length.as_micros()
+    );
+    if let Some(supported) = intervals::supported()
+        && !supported.supports(CONN_RATE_PARAMS.min_connection_interval)
+    {
+        warn!(
+            "{}us is not in the controller's supported interval table",
+            CONN_RATE_PARAMS.min_connection_interval.as_micros()
+        );
+    }
+
+    let negotiation = ladder::negotiate(stack, conn).await;
+    negotiation.log();
+    negotiation.agreed().is_some()
+}
+
+async fn request_subrate<C: SciController>(
+    stack: &Stack<'_, C, DefaultPacketPool>,
+    conn: &Connection<'_, DefaultPacketPool>,
+    p: &SubrateParams,
+) -> bool {
+    info!(
+        "Requesting subrate: {}-{}, latency={}, cont={}, timeout={}ms",
+        p.subrate_min,
+        p.subrate_max,
+        p.max_latency,
+        p.continuation_number,
+        p.supervision_timeout_ms
+    );
+
+    let command = LeSubrateRequest::new(
+        conn.handle(),
+        p.subrate_min,
+        p.subrate_max,
+        p.max_latency,
+        p.continuation_number,
+        HciDuration::from_millis(p.supervision_timeout_ms as u32),
+    );
+    for i in 0..RATE_REQUEST_ATTEMPTS {
+        match stack.async_command(command).await {
+            Ok(_) => {
+                info!("Subrate request sent successfully");
+                return true;
+            }
+            Err(e) => {
+                warn!(
+                    "Subrate request failed (retry {}/{}): {:?}",
+                    i, RATE_REQUEST_ATTEMPTS, e
+                );
+                match TEST_CONFIG.bring_up {
+                    BringUp::Serial => Timer::after(RETRY_PAUSE).await,
+                    BringUp::Overlapped => {
+                        wait_for(conn, |_| true).await;
+                    }
+                }
+            }
+        }
+    }
+    false
+}
+
+/// Wait for a connection event matching `done`, false on timeout or disconnection
+pub(super) async fn wait_for(
+    conn: &Connection<'_, DefaultPacketPool>,
+    done: impl Fn(&ConnectionEvent) -> bool,
+) -> bool {
+    let event = async {
+        loop {
+            match conn.next().await {
+                ConnectionEvent::Disconnected { .. } => return false,
+                event if done(&event) => return true,
+                _ => {}
+            }
+        }
+    };
+    with_timeout(PROCEDURE_TIMEOUT, event)
+        .await
+        .unwrap_or(false)
+}
+
+async fn set_tx_power<C: SciController>(
+    stack: &Stack<'_, C, DefaultPacketPool>,
+    conn: &Connection<'_, DefaultPacketPool>,
+) -> Option<i8> {
+    let requested = TEST_CONFIG.radio.central_tx_power_dbm;
+    match power::set_tx_power(stack, conn, requested).await {
+        Ok(selected) => {
+            info!(
+                "TX power set to {} dBm ({} dBm requested)",
+                selected, requested
+            );
+            Some(selected)
+        }
+        Err(e) => {
+            warn!("Failed to set TX power: {:?}", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! Burst traffic over continuation numbers and subrate factors.
+//!
+//! In [`TestMode::Burst`] every trigger makes the peripheral queue
+//! [`BurstConfig::notifications`] notifications back to back. The central
+//! timestamps their arrival and splits them into connection events by the
+//! gaps between them, which shows whether the controller fits them into the
+//! subrated event that carried the trigger, continues on the base interval for
+//! `continuation_number` events, or leaves the rest for later subrated events.
+//! [`BurstSweep`] walks every combination of continuation number and subrate
+//! factor in [`TEST_CONFIG`], with the energy the model estimates for each.
+//! Continuation numbers not below the subrate factor are invalid and skipped.
+//! A step is only measured once the controller reports its rate and the QoS
+//! reports show its subrate, a step that can't be put in effect is skipped.
+//!
+//! [`TestMode::Burst`]: crate::config::TestMode::Burst
+
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use trouble_host::prelude::*;
+
+use super::bringup::wait_for;
+use super::qos::QOS;
+use super::{CONN_RATE_PARAMS, SciController, rate_params};
+use crate::config::{BurstConfig, TEST_CONFIG};
+use crate::energy::{self, LinkModel};
+use crate::lfclk;
+use crate::stats::LatencyHistogram;
+use crate::watchdog::{self, Task};
+
+const NOTIFICATIONS_MAX: usize = BurstConfig::MAX_NOTIFICATIONS as usize;
+
+/// Longest wait for the QoS reports to show a step's subrate
+const SUBRATE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often the QoS reports are looked at meanwhile
+const SUBRATE_POLL: Duration = Duration::from_millis(10);
+
+/// Why a step's rate isn't in effect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StepError {
+    /// The host or controller failed the request
+    Request,
+    /// The controller never reported the new rate
+    NotApplied,
+    /// The QoS reports never showed the step's subrate
+    Unconfirmed,
+}
+
+/// Arrival times of the notifications of one burst
+pub struct Burst {
+    sent_at: Instant,
+    arrivals: [Instant; NOTIFICATIONS_MAX],
+    received: usize,
+}
+
+impl Burst {
+    /// Start timing a burst, right before its trigger goes out
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            sent_at: now,
+            arrivals: [now; NOTIFICATIONS_MAX],
+            received: 0,
+        }
+    }
+
+    pub fn arrived(&mut self) {
+        if let Some(slot) = self.arrivals.get_mut(self.received) {
+            *slot = Instant::now();
+            self.received += 1;
+        }
+    }
+
+    /// Trigger until the last notification that arrived
+    pub fn duration(&self) -> Option<Duration> {
+        let last = self.arrivals[..self.received].last()?;
+        Some(*last - self.sent_at)
+    }
+}
+
+/// How the bursts of one sweep step spread over connection events
+struct BurstStats {
+    bursts: u32,
+    /// Bursts that lost a notification
+    incomplete: u32,
+    /// Index `n - 1` counts bursts with `n` notifications in their first event
+    first_event: [u32; NOTIFICATIONS_MAX],
+    /// Connection events spanned, summed over all bursts
+    events: u32,
+    /// Trigger until the first notification
+    first_latency: LatencyHistogram,
+    /// First until the last notification
+    spread: LatencyHistogram,
+}
+
+impl BurstStats {
+    const fn new() -> Self {
+        Self {
+            bursts: 0,
+            incomplete: 0,
+            first_event: [0; NOTIFICATIONS_MAX],
+            events: 0,
+            first_latency: LatencyHistogram::new(),
+            spread: LatencyHistogram::new(),
+        }
+    }
+
+    fn record(&mut self, burst: &Burst, interval: Duration) {
+        self.bursts += 1;
+        if burst.received < TEST_CONFIG.burst.notifications as usize {
+            self.incomplete += 1;
+        }
+        let Some((&first, rest)) = burst.arrivals[..burst.received].split_first() else {
+            return;
+        };
+
+        // Packets of one event arrive within a few hundred µs, events are an interval apart
+        let same_event = interval / 2;
+        let mut events = 1;
+        let mut in_first = 1;
+        let mut last = first;
+        for &arrival in rest {
+            if arrival - last > same_event {
+                events += 1;
+            } else if events == 1 {
+                in_first += 1;
+            }
+            last = arrival;
+        }
+
+        self.first_event[in_first - 1] += 1;
+        self.events += events;
+        self.first_latency.record(first - burst.sent_at);
+        self.spread.record(last - first);
+    }
+}
+
+/// Walks the continuation numbers and subrate factors of [`BurstConfig`]
+pub struct BurstSweep {
+    step: usize,
+    /// Whether the step's rate is in effect, bursts are only measured then
+    applied: bool,
+    stats: BurstStats,
+    started: Instant,
+}
+
+impl BurstSweep {
+    pub fn new() -> Self {
+        Self {
+            step: 0,
+            applied: false,
+            stats: BurstStats::new(),
+            started: Instant::now(),
+        }
+    }
+
+    fn steps() -> usize {
+        TEST_CONFIG.burst.valid_combinations()
+    }
+
+    /// `(subrate_factor, continuation_number)` of the current step
+    fn combination(&self) -> (u16, u16) {
+        TEST_CONFIG
+            .burst
+            .combinations()
+            .nth(self.step)
+            .expect("steps are the valid combinations")
+    }
+
+    fn params(&self) -> ConnectRateParams {
+        let (subrate, continuation_number) = self.combination();
+        ConnectRateParams {
+            subrate_min: subrate,
+            subrate_max: subrate,
+            continuation_number,
+            ..CONN_RATE_PARAMS
+        }
+    }
+
+    /// Idle time before a trigger, so it lands in a subrated event after any continuation
+    pub fn idle_gap(&self) -> Duration {
+        let (subrate, continuation_number) = self.combination();
+        let intervals = subrate as u32 * (continuation_number as u32 + 2);
+        CONN_RATE_PARAMS.min_connection_interval * intervals
+    }
+
+    pub fn record(&mut self, burst: &Burst) {
+        if self.applied {
+            self.stats
+                .record(burst, CONN_RATE_PARAMS.min_connection_interval);
+        } else {
+            // Only counted to pace the next attempt
+            self.stats.bursts += 1;
+        }
+    }
+
+    /// Log the step once it has all its bursts and move on, true if the step changed
+    pub fn step_done(&mut self) -> bool {
+        if self.stats.bursts < TEST_CONFIG.burst.bursts_per_step {
+            return false;
+        }
+        if self.applied {
+            self.log();
+        }
+        self.next();
+        true
+    }
+
+    fn next(&mut self) {
+        self.step = (self.step + 1) % Self::steps();
+        self.stats = BurstStats::new();
+        self.started = Instant::now();
+    }
+
+    /// Put the current step's subrate and continuation number in effect,
+    /// skipping to the next step for as long as one fails
+    pub async fn request<C: SciController>(
+        &mut self,
+        stack: &Stack<'_, C, DefaultPacketPool>,
+        conn: &Connection<'_, DefaultPacketPool>,
+    ) {
+        for _ in 0..Self::steps() {
+            // Each step may wait out the subrate timeout
+            watchdog::check_in(Task::TestLoop);
+            let (subrate, continuation_number) = self.combination();
+            match apply(stack, conn, &self.params()).await {
+                Ok(()) => {
+                    info!(
+                        "Burst step {}/{}: subrate {}, continuation number {}",
+                        self.step + 1,
+                        Self::steps(),
+                        subrate,
+                        continuation_number
+                    );
+                    self.applied = true;
+                    self.stats = BurstStats::new();
+                    self.started = Instant::now();
+                    return;
+                }
+                Err(e) => {
+                    warn!(
+                        "Burst step {}/{} skipped, subrate {}, continuation number {}: {:?}",
+                        self.step + 1,
+                        Self::steps(),
+                        subrate,
+                        continuation_number,
+                        e
+                    );
+                    self.next();
+                }
+            }
+        }
+        warn!("No burst step could be applied, bursts aren't measured until one is");
+        self.applied = false;
+    }
+
+    fn log(&self) {
+        let (subrate, continuation_number) = self.combination();
+        let s = &self.stats;
+        let events_x100 = (s.events * 100).checked_div(s.bursts).unwrap_or(0);
+        info!(
+            "Bursts of {}, subrate {}, continuation number {}: {} bursts ({} incomplete), {}.{:02} events per burst",
+            TEST_CONFIG.burst.notifications,
+            subrate,
+            continuation_number,
+            s.bursts,
+            s.incomplete,
+            events_x100 / 100,
+            events_x100 % 100
+        );
+        for (n, &count) in (1..).zip(&s.first_event).filter(|(_, c)| **c > 0) {
+            info!("  {} in the first event: {} bursts", n, count);
+        }
+        let first = s.first_latency.summary();
+        let spread = s.spread.summary();
+        info!(
+            "  Trigger to first: mean {}us p99 {}us, first to last: mean {}us p99 {}us max {}us",
+            first.mean_us, first.p99_us, spread.mean_us, spread.p99_us, spread.max_us
+        );
+
+        // The trigger and its notifications
+        let packets = s.bursts as u64 * (TEST_CONFIG.burst.notifications as u64 + 1);
+        let elapsed_us = self.started.elapsed().as_micros().max(1);
+        let packets_per_second = (packets * 1_000_000 / elapsed_us) as u32;
+        let link = LinkModel::from_params(
+            &rate_params(&self.params()),
+            packets_per_second,
+            lfclk::active().accuracy_ppm,
+        );
+        energy::estimate(&link).log();
+    }
+}
+
+/// Request a sweep step's rate, with a single subrate factor, and wait until
+/// the controller and the QoS reports show it
+pub(super) async fn apply<C: SciController>(
+    stack: &Stack<'_, C, DefaultPacketPool>,
+    conn: &Connection<'_, DefaultPacketPool>,
+    params: &ConnectRateParams,
+) -> Result<(), StepError> {
+    let subrate = params.subrate_max;
+    let gap_before = QOS.event_gap().map(|(gap, _)| gap);
+    let requested_at = Instant::now();
+
+    if let Err(e) = conn.request_connection_rate(stack, params).await {
+        warn!("Failed to request sweep step rate: {:?}", e);
+        return Err(StepError::Request);
+    }
+    let intervals = params.min_connection_interval..=params.max_connection_interval;
+    let reported = wait_for(conn, |e| {
+        matches!(
+            e,
+            ConnectionEvent::ConnectionParamsUpdated { conn_interval, .. }
+                if intervals.contains(conn_interval)
+        )
+    })
+    .await;
+    if !reported {
+        return Err(StepError::NotApplied);
+    }
+
+    // An unchanged gap has no new report to show for it
+    if gap_before == Some(subrate) {
+        return Ok(());
+    }
+    let confirmed = async {
+        loop {
+            match QOS.event_gap() {
+                Some((gap, since)) if gap == subrate && since >= requested_at => return,
+                _ => Timer::after(SUBRATE_POLL).await,
+            }
+        }
+    };
+    with_timeout(SUBRATE_TIMEOUT, confirmed)
+        .await
+        .map_err(|_| StepError::Unconfirmed)
+}
@@ -0,0 +1,754 @@
+use core::future::pending;
+
+use embassy_futures::{
+    join::join,
+    select::{Either, select},
+};
+use embassy_time::{Duration, Instant, Timer, with_deadline, with_timeout};
+use trouble_host::prelude::*;
+
+use super::adaptive::AdaptivePolicy;
+use super::bringup::{Step, Timeline, initial_conn_params, set_default_rate, set_up_link};
+use super::burst::{Burst, BurstSweep};
+use super::discovery::{DiscoveryCache, PeerHandles};
+use super::power::PathLossMonitor;
+use super::wakeup::WakeUpSweep;
+use super::{
+    ECHO_FLAG, ORIGINATED_FLAG, PERIPHERAL_ADDR_BYTES, SciController, channels,
+    connect_rate_params, power, qos, run_runner, set_host_features,
+};
+use crate::config::{
+    BringUp, ConnectStrategy, LatencyBudget, LfClockPlan, PingRequest, PingResponse, TEST_CONFIG,
+    TestMode, TrafficPattern,
+};
+use crate::control::{
+    CONTROL_POINT_LEN, Command, ControlError, IntervalPage, Opcode, Response, RttDistribution,
+    StatsSummary,
+};
+use crate::intervals::{self, SupportedIntervals};
+use crate::lfclk::{self, RunResult};
+use crate::random::Xorshift32;
+use crate::solver;
+use crate::stats::Stats;
+use crate::watchdog::{self, Task};
+
+/// How long the peer may take to answer a control-point request
+const CONTROL_RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Pings between fetching the peripheral's own round-trip statistics
+const PEER_STATS_EVERY: u32 = 1000;
+
+/// Longest wait for the next notification of a burst
+const BURST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Longest wait for the answer to a ping, well past a subrated event at the
+/// rates the tests use, the ping has failed after it
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub(super) async fn run<C: SciController>(stack: &Stack<'_, C, DefaultPacketPool>) {
+    let Host {
+        mut central,
+        mut runner,
+        ..
+    } = stack.build();
+    let target = Address::random(PERIPHERAL_ADDR_BYTES);
+
+    let config = ConnectConfig {
+        connect_params: initial_conn_params(),
+        scan_config: ScanConfig {
+            filter_accept_list: &[(target.kind, &target.addr)],
+            // The peripheral advertises legacy on LE 1M, links move to 2M after connecting
+            phys: PhySet::M1,
+            ..Default::default()
+        },
+    };
+
+    join(run_runner(&mut runner, stack), async {
+        // Enable host features for Connection Subrating and Shorter Connection Intervals
+        set_host_features(stack).await;
+        qos::enable(stack).await;
+        channels::classify(stack, TEST_CONFIG.radio.channels).await;
+        set_default_rate(stack).await;
+
+        let mut discovery = DiscoveryCache::new();
+
+        // This boot's LF clock run, kept across reconnections
+        let mut clock_run = Stats::new();
+        let mut link_losses = 0;
+        let run_started = Instant::now();
+
+        loop {
+            info!("Connecting to {:?}...", target);
+            watchdog::check_in(Task::TestLoop);
+            let connect = async {
+                match TEST_CONFIG.connect {
+                    ConnectStrategy::UpdateAfterConnect => central.connect(&config).await,
+                    ConnectStrategy::Direct => central.connect_ext(&config).await,
+                }
+            };
+            match watchdog::waiting(Task::TestLoop, connect).await {
+                Ok(conn) => {
+                    watchdog::check_in(Task::TestLoop);
+                    qos::QOS.reset();
+                    let timeline = Timeline::new();
+
+                    // Serial bring-up finishes the link layer before GATT starts
+                    let serial_tx_power = match TEST_CONFIG.bring_up {
+                        BringUp::Serial => Some(set_up_link(stack, &conn, &timeline).await),
+                        BringUp::Overlapped => None,
+                    };
+
+                    let client = match timeline
+                        .time(
+                            Step::GattClient,
+                            GattClient::<_, DefaultPacketPool, 10>::new(stack, &conn),
+                        )
+                        .await
+                    {
+                        Ok(c) => c,
+                        Err(e) => {
+                            warn!("Failed to create GATT client: {:?}", e);
+                            continue;
+                        }
+                    };
+
+                    let peer = conn.peer_address();
+                    let _ = join(client.task(), async {
+                        let gatt = async {
+                            let discovered = match timeline
+                                .time(Step::Discovery, discovery.discover(peer, &client))
+                                .await
+                            {
+                                Ok(d) => d,
+                                Err(e) => {
+                                    warn!("Discovery failed: {}", e);
+                                    return None;
+                                }
+                            };
+                            match discovered.full_discovery {
+                                Some(full) => info!(
+                                    "Handles from cache in {}us (full discovery took {}us, saved {}us)",
+                                    discovered.elapsed.as_micros(),
+                                    full.as_micros(),
+                                    full.as_micros()
+                                        .saturating_sub(discovered.elapsed.as_micros())
+                                ),
+                                None => info!(
+                                    "Full discovery took {}us",
+                                    discovered.elapsed.as_micros()
+                                ),
+                            }
+                            let handles = discovered.handles;
+
+                            let subscribed = timeline
+                                .time(Step::Subscribe, async {
+                                    let indicate =
+                                        TEST_CONFIG.ping.response == PingResponse::Indication;
+                                    let listener =
+                                        match client.subscribe(&handles.counter, indicate).await {
+                                            Ok(l) => l,
+                                            Err(e) => {
+                                                warn!("Failed to subscribe: {:?}", e);
+                                                // Handles may be stale, rediscover on the next connection
+                                                discovery.invalidate(peer);
+                                                return None;
+                                            }
+                                        };
+
+                                    let control =
+                                        match client.subscribe(&handles.control_point, true).await {
+                                            Ok(l) => l,
+                                            Err(e) => {
+                                                warn!(
+                                                    "Failed to subscribe to control point: {:?}",
+                                                    e
+                                                );
+                                                return None;
+                                            }
+                                        };
+
+                                    let service_changed = match &handles.service_changed {
+                                        Some(c) => client.subscribe(c, true).await.ok(),
+                                        None => None,
+                                    };
+                                    Some((listener, control, service_changed))
+                                })
+                                .await;
+                            let (listener, mut control, service_changed) = subscribed?;
+
+                            let configured = timeline
+                                .time(
+                                    Step::PeerConfig,
+                                    configure_peer(&client, &handles.control_point, &mut control),
+                                )
+                                .await;
+                            match configured {
+                                Ok(peer_tx_power) => Some((
+                                    handles,
+                                    listener,
+                                    control,
+                                    service_changed,
+                                    peer_tx_power,
+                                )),
+                                Err(e) => {
+                                    warn!("Failed to configure peer: {}", e);
+                                    None
+                                }
+                            }
+                        };
+
+                        let link = async {
+                            match serial_tx_power {
+                                Some(tx_power) => tx_power,
+                                None => set_up_link(stack, &conn, &timeline).await,
+                            }
+                        };
+
+                        let (tx_power, gatt) = join(link, gatt).await;
+                        let Some((
+                            handles,
+                            mut listener,
+                            mut control,
+                            mut service_changed,
+                            peer_tx_power,
+                        )) = gatt
+                        else {
+                            conn.disconnect();
+                            return;
+                        };
+                        let PeerHandles {
+                            counter: counter_char,
+                            command: command_char,
+                            control_point,
+                            ..
+                        } = handles;
+
+                        watchdog::check_in(Task::TestLoop);
+                        timeline.log();
+
+                        if let Some(budget) = &TEST_CONFIG.budget {
+                            let peer_intervals =
+                                fetch_peer_intervals(&client, &control_point, &mut control).await;
+                            if let Err(e) = &peer_intervals {
+                                warn!(
+                                    "Peer intervals unknown, solving with the local table: {}",
+                                    e
+                                );
+                            }
+                            let peer = peer_intervals.ok();
+                            apply_budget(stack, &conn, budget, peer.as_ref()).await;
+                            watchdog::check_in(Task::TestLoop);
+                        }
+                        info!(
+                            "Starting {:?} with {:?}",
+                            TEST_CONFIG.mode, TEST_CONFIG.ping
+                        );
+
+                        let mut stats = Stats::new();
+                        stats.signal.tx_power_dbm = tx_power;
+                        let mut seq: u8 = 0;
+                        let mut last_echo: Option<Instant> = None;
+
+                        // Path loss is measured against the level the peer transmits at
+                        let mut path_loss = match (TEST_CONFIG.radio.path_loss, peer_tx_power) {
+                            (Some(zones), Some(tx)) => Some(PathLossMonitor::new(zones, tx)),
+                            (Some(_), None) => {
+                                warn!("Peer TX power unknown, not tracking path loss");
+                                None
+                            }
+                            (None, _) => None,
+                        };
+                        let mut next_rssi = Instant::now();
+
+                        let mut burst_sweep = BurstSweep::new();
+                        if TEST_CONFIG.mode == TestMode::Burst {
+                            burst_sweep.request(stack, &conn).await;
+                        }
+
+                        let mut wake_up = WakeUpSweep::new();
+                        let mut rng = Xorshift32::from_uptime();
+                        if TEST_CONFIG.mode == TestMode::PeripheralLatency {
+                            wake_up.request(stack, &conn).await;
+                        }
+
+                        let mut policy = TEST_CONFIG.adaptive.map(AdaptivePolicy::new);
+
+                        loop {
+                            watchdog::check_in(Task::TestLoop);
+                            let db_changed = async {
+                                match &mut service_changed {
+                                    Some(l) => l.next().await,
+                                    None => pending().await,
+                                }
+                            };
+                            if let Some(policy) = &mut policy {
+                                policy.on_exchange();
+                                policy.update(stack, &conn).await;
+                            }
+                            let exchange = async {
+                                match TEST_CONFIG.mode {
+                                    TestMode::PingPong => {
+                                        ping_once(
+                                            &client,
+                                            &counter_char,
+                                            &command_char,
+                                            &mut listener,
+                                            seq,
+                                        )
+                                        .await
+                                    }
+                                    TestMode::PeripheralPing => {
+                                        echo_once(
+                                            &client,
+                                            &command_char,
+                                            &mut listener,
+                                            seq,
+                                            &mut last_echo,
+                                        )
+                                        .await
+                                    }
+                                    TestMode::Burst => {
+                                        burst_once(
+                                            &client,
+                                            &command_char,
+                                            &mut listener,
+                                            seq,
+                                            &mut burst_sweep,
+                                        )
+                                        .await
+                                    }
+                                    TestMode::PeripheralLatency => {
+                                        wakeup_once(
+                                            &client,
+                                            &command_char,
+                                            &mut listener,
+                                            seq,
+                                            &mut wake_up,
+                                            &mut rng,
+                                        )
+                                        .await
+                                    }
+                                }
+                            };
+
+                            match select(exchange, db_changed).await {
+                                Either::First(Ok(rtt)) => {
+                                    if let Some(rtt) = rtt {
+                                        stats.rtt.record(rtt);
+                                        clock_run.rtt.record(rtt);
+                                    }
+                                    stats.packets.sent += 1;
+                                    stats.packets.received += 1;
+                                    clock_run.packets.sent += 1;
+                                }
+                                Either::First(Err(e)) => {
+                                    warn!("Ping-pong broken: {:?}", e);
+                                    link_losses += 1;
+                                    break;
+                                }
+                                Either::Second(_) => {
+                                    warn!("Peer database changed, dropping cached handles");
+                                    discovery.invalidate(peer);
+                                    conn.disconnect();
+                                    break;
+                                }
+                            }
+                            seq = seq.wrapping_add(1);
+
+                            if let TrafficPattern::OnOff {
+                                exchanges,
+                                pause_ms,
+                            } = TEST_CONFIG.traffic
+                                && stats.packets.sent % exchanges == 0
+                            {
+                                let pause = Duration::from_millis(pause_ms as u64);
+                                match &mut policy {
+                                    Some(policy) => policy.idle(stack, &conn, pause).await,
+                                    None => Timer::after(pause).await,
+                                }
+                            }
+
+                            if TEST_CONFIG.mode == TestMode::Burst && burst_sweep.step_done() {
+                                burst_sweep.request(stack, &conn).await;
+                            }
+
+                            if TEST_CONFIG.mode == TestMode::PeripheralLatency
+                                && wake_up.step_complete()
+                            {
+                                let peer = control_request(
+                                    &client,
+                                    &control_point,
+                                    &mut control,
+                                    Command::FetchRtt,
+                                )
+                                .await
+                                .and_then(|r| RttDistribution::decode(r.payload()));
+                                if let Err(e) = &peer {
+                                    warn!("Failed to fetch peer RTT: {}", e);
+                                }
+                                wake_up.finish(peer.ok());
+                                wake_up.request(stack, &conn).await;
+                                // The peripheral's next step starts from an empty histogram
+                                if let Err(e) = control_request(
+                                    &client,
+                                    &control_point,
+                                    &mut control,
+                                    Command::ResetCounters,
+                                )
+                                .await
+                                {
+                                    warn!("Failed to reset peer counters: {}", e);
+                                }
+                            }
+
+                            if Instant::now() >= next_rssi {
+                                next_rssi = Instant::now() + power::RSSI_PERIOD;
+                                if let Ok(rssi) = power::read_rssi(stack, &conn).await {
+                                    stats.signal.record_rssi(rssi);
+                                    if let Some(monitor) = &mut path_loss
+                                        && let Some(zone) = monitor.update(rssi)
+                                    {
+                                        info!(
+                                            "Path loss {} dB, now in the {:?} zone",
+                                            monitor.path_loss(rssi),
+                                            zone
+                                        );
+                                    }
+                                }
+                            }
+
+                            if let LfClockPlan::Compare { pings_per_source } = TEST_CONFIG.lf_clock
+                                && clock_run.packets.sent >= pings_per_source
+                            {
+                                let rtt = clock_run.rtt.summary();
+                                lfclk::finish_run(RunResult {
+                                    pings: clock_run.packets.sent,
+                                    link_losses,
+                                    duration_ms: run_started.elapsed().as_millis() as u32,
+                                    rtt_mean_us: rtt.mean_us,
+                                    rtt_p99_us: rtt.p99_us,
+                                    rtt_max_us: rtt.max_us,
+                                });
+                            }
+
+                            if stats.packets.sent % 100 == 0 {
+                                let rtt = stats.rtt.summary();
+                                info!(
+                                    "Central-initiated RTT: n={} mean {}us p50 {}us p99 {}us max {}us",
+                                    rtt.count, rtt.mean_us, rtt.p50_us, rtt.p99_us, rtt.max_us
+                                );
+                                log_signal(&stats, path_loss.as_ref());
+                                stats.signal.start_window();
+                                if let Some(policy) = &policy {
+                                    policy.log();
+                                }
+                            }
+
+                            if stats.packets.sent % PEER_STATS_EVERY == 0 {
+                                // The interrupted round trip is not sampled on either side
+                                last_echo = None;
+                                match control_request(
+                                    &client,
+                                    &control_point,
+                                    &mut control,
+                                    Command::FetchStats,
+                                )
+                                .await
+                                .and_then(|r| StatsSummary::decode(r.payload()))
+                                {
+                                    Ok(peer) => info!(
+                                        "Peripheral-initiated RTT: mean {}us p99 {}us ({} pings)",
+                                        peer.rtt_mean_us, peer.rtt_p99_us, peer.pings
+                                    ),
+                                    Err(e) => warn!("Failed to fetch peer stats: {}", e),
+                                }
+                                qos::log_channel_errors(&qos::QOS.channel_errors());
+                            }
+                        }
+                    })
+                    .await;
+                    qos::QOS.reset();
+                }
+                Err(e) => warn!("Connect failed: {:?}", e),
+            }
+            Timer::after(Duration::from_secs(2)).await;
+        }
+    })
+    .await;
+}
+
+fn log_signal(stats: &Stats, path_loss: Option<&PathLossMonitor>) {
+    let signal = &stats.signal;
+    let Some(mean) = signal.rssi_mean() else {
+        return;
+    };
+    info!(
+        "Signal: TX {} dBm, RSSI mean {} dBm min {} max {} ({} samples)",
+        signal.tx_power_dbm.unwrap_or(0),
+        mean,
+        signal.rssi_min,
+        signal.rssi_max,
+        signal.rssi_samples
+    );
+    if let Some(monitor) = path_loss {
+        info!(
+            "Path loss: {} dB ({:?} zone)",
+            monitor.path_loss(mean),
+            monitor.zone()
+        );
+    }
+}
+
+/// Send one ping with the configured request primitive and wait for the answer
+///
+/// Returns the central-initiated round trip, an answer that doesn't arrive
+/// within [`PING_TIMEOUT`] fails the ping.
+async fn ping_once<C: Controller, P: PacketPool, const MAX_SERVICES: usize, const MTU: usize>(
+    client: &GattClient<'_, C, P, MAX_SERVICES>,
+    counter: &Characteristic<u32>,
+    command: &Characteristic<u8>,
+    listener: &mut NotificationListener<'_, MTU>,
+    seq: u8,
+) -> Result<Option<Duration>, BleHostError<C::Error>> {
+    let sent_at = Instant::now();
+    if TEST_CONFIG.ping.request == PingRequest::Read {
+        let mut value = [0; 4];
+        client.read_characteristic(counter, &mut value).await?;
+    } else {
+        write_command(client, command, seq).await?;
+        with_timeout(PING_TIMEOUT, listener.next())
+            .await
+            .map_err(|_| BleHostError::BleHost(Error::Timeout))?;
+    }
+    Ok(Some(sent_at.elapsed()))
+}
+
+/// Wait for the peripheral's ping and echo it with the configured request primitive
+///
+/// Returns the time from the previous echo until this ping arrived, the
+/// round trip as seen from the central in [`TestMode::PeripheralPing`].
+async fn echo_once<C: Controller, P: PacketPool, const MAX_SERVICES: usize, const MTU: usize>(
+    client: &GattClient<'_, C, P, MAX_SERVICES>,
+    command: &Characteristic<u8>,
+    listener: &mut NotificationListener<'_, MTU>,
+    seq: u8,
+    last_echo: &mut Option<Instant>,
+) -> Result<Option<Duration>, BleHostError<C::Error>> {
+    listener.next().await;
+    let rtt = last_echo.map(|sent| sent.elapsed());
+
+    *last_echo = Some(Instant::now());
+    write_command(client, command, seq).await?;
+    Ok(rtt)
+}
+
+/// Trigger a burst once the link has gone idle and collect its notifications
+///
+/// Returns the time from the trigger until the last notification.
+async fn burst_once<C: Controller, P: PacketPool, const MAX_SERVICES: usize, const MTU: usize>(
+    client: &GattClient<'_, C, P, MAX_SERVICES>,
+    command: &Characteristic<u8>,
+    listener: &mut NotificationListener<'_, MTU>,
+    seq: u8,
+    sweep: &mut BurstSweep,
+) -> Result<Option<Duration>, BleHostError<C::Error>> {
+    Timer::after(sweep.idle_gap()).await;
+
+    let mut burst = Burst::new();
+    write_command(client, command, seq).await?;
+    for _ in 0..TEST_CONFIG.burst.notifications {
+        if with_timeout(BURST_TIMEOUT, listener.next()).await.is_err() {
+            break;
+        }
+        burst.arrived();
+    }
+
+    sweep.record(&burst);
+    Ok(burst.duration())
+}
+
+/// Send data at a random time and wait for the peripheral's answer
+///
+/// Data the peripheral originates meanwhile is echoed with [`ECHO_FLAG`] set.
+/// Returns the central-originated round trip.
+async fn wakeup_once<C: Controller, P: PacketPool, const MAX_SERVICES: usize, const MTU: usize>(
+    client: &GattClient<'_, C, P, MAX_SERVICES>,
+    command: &Characteristic<u8>,
+    listener: &mut NotificationListener<'_, MTU>,
+    seq: u8,
+    sweep: &mut WakeUpSweep,
+    rng: &mut Xorshift32,
+) -> Result<Option<Duration>, BleHostError<C::Error>> {
+    let seq = seq & !ECHO_FLAG;
+    let send_at = Instant::now() + rng.pause(WakeUpSweep::max_gap());
+    while let Ok(notification) = with_deadline(send_at, listener.next()).await {
+        if originated(notification.as_ref()) {
+            write_command(client, command, seq | ECHO_FLAG).await?;
+        }
+    }
+
+    let sent_at = Instant::now();
+    write_command(client, command, seq).await?;
+    while originated(listener.next().await.as_ref()) {
+        write_command(client, command, seq | ECHO_FLAG).await?;
+    }
+    let rtt = sent_at.elapsed();
+
+    sweep.record(rtt);
+    Ok(Some(rtt))
+}
+
+/// Whether a counter notification carries [`ORIGINATED_FLAG`]
+fn originated(value: &[u8]) -> bool {
+    value
+        .get(..4)
+        .is_some_and(|v| u32::from_le_bytes(v.try_into().unwrap()) & ORIGINATED_FLAG != 0)
+}
+
+async fn write_command<C: Controller, P: PacketPool, const MAX_SERVICES: usize>(
+    client: &GattClient<'_, C, P, MAX_SERVICES>,
+    command: &Characteristic<u8>,
+    seq: u8,
+) -> Result<(), BleHostError<C::Error>> {
+    match TEST_CONFIG.ping.request {
+        PingRequest::Write => client.write_characteristic(command, &[seq]).await,
+        PingRequest::WriteWithoutResponse => {
+            client
+                .write_characteristic_without_response(command, &[seq])
+                .await
+        }
+        PingRequest::Read => unreachable!("read polling cannot carry a ping"),
+    }
+}
+
+/// Read the peer's supported interval table one group at a time
+async fn fetch_peer_intervals<
+    C: Controller,
+    P: PacketPool,
+    const MAX_SERVICES: usize,
+    const MTU: usize,
+>(
+    client: &GattClient<'_, C, P, MAX_SERVICES>,
+    control_point: &Characteristic<[u8; CONTROL_POINT_LEN]>,
+    responses: &mut NotificationListener<'_, MTU>,
+) -> Result<SupportedIntervals, ControlError> {
+    let mut table: Option<SupportedIntervals> = None;
+    let mut index = 0;
+    loop {
+        let command = Command::FetchIntervals(index);
+        let page = control_request(client, control_point, responses, command)
+            .await
+            .and_then(|r| IntervalPage::decode(r.payload()))?;
+        let table = table.get_or_insert_with(|| SupportedIntervals::new(page.minimum as u16));
+        table
+            .push(page.group)
+            .map_err(|_| ControlError::MalformedResponse)?;
+
+        index += 1;
+        if index >= page.num_groups {
+            return Ok(*table);
+        }
+    }
+}
+
+/// Solve the latency budget against both interval tables and request the result
+async fn apply_budget<C: SciController>(
+    stack: &Stack<'_, C, DefaultPacketPool>,
+    conn: &Connection<'_, DefaultPacketPool>,
+    budget: &LatencyBudget,
+    peer: Option<&SupportedIntervals>,
+) {
+    let Some(local) = intervals::supported() else {
+        warn!("Local interval table unknown, keeping the target rate");
+        return;
+    };
+    let sca_ppm = lfclk::active().accuracy_ppm;
+    let solution = match solver::solve(budget, &local, peer, sca_ppm) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!(
+                "No parameters meet the latency budget, keeping the target rate: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    solution.log(budget);
+    if let Err(e) = conn
+        .request_connection_rate(stack, &connect_rate_params(&solution.params))
+        .await
+    {
+        warn!("Failed to request the solved connection rate: {:?}", e);
+    }
+}
+
+/// Stop the peer's test, apply [`TEST_CONFIG`] and start it again
+///
+/// Returns the TX power the peer selected, if it could set one.
+async fn configure_peer<
+    C: Controller,
+    P: PacketPool,
+    const MAX_SERVICES: usize,
+    const MTU: usize,
+>(
+    client: &GattClient<'_, C, P, MAX_SERVICES>,
+    control_point: &Characteristic<[u8; CONTROL_POINT_LEN]>,
+    responses: &mut NotificationListener<'_, MTU>,
+) -> Result<Option<i8>, ControlError> {
+    let commands = [
+        Command::StopTest,
+        Command::SelectMode(TEST_CONFIG.mode),
+        Command::SelectPrimitives(TEST_CONFIG.ping),
+        Command::SetTxPower(TEST_CONFIG.radio.peripheral_tx_power_dbm),
+        Command::SetBurstSize(TEST_CONFIG.burst.notifications),
+        Command::ResetCounters,
+        Command::StartTest,
+    ];
+
+    let mut peer_tx_power = None;
+    for command in commands {
+        let opcode = command.opcode();
+        match control_request(client, control_point, responses, command).await {
+            Ok(response) if opcode == Opcode::SetTxPower => {
+                peer_tx_power = response.payload().first().map(|&dbm| dbm as i8);
+            }
+            // The test runs fine at the peer's default level
+            Err(e) if opcode == Opcode::SetTxPower => warn!("Peer TX power not set: {}", e),
+            // The peer was not running a test yet
+            Ok(_) | Err(ControlError::InvalidState(Opcode::StopTest)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(peer_tx_power)
+}
+
+/// Write a control-point request and wait for the indicated response
+async fn control_request<
+    C: Controller,
+    P: PacketPool,
+    const MAX_SERVICES: usize,
+    const MTU: usize,
+>(
+    client: &GattClient<'_, C, P, MAX_SERVICES>,
+    control_point: &Characteristic<[u8; CONTROL_POINT_LEN]>,
+    responses: &mut NotificationListener<'_, MTU>,
+    command: Command,
+) -> Result<Response, ControlError> {
+    let opcode = command.opcode();
+    let mut request = [0; CONTROL_POINT_LEN];
+    let len = command.encode(&mut request);
+
+    if let Err(e) = client
+        .write_characteristic(control_point, &request[..len])
+        .await
+    {
+        warn!("Failed to write control point: {:?}", e);
+        return Err(ControlError::Failed(opcode));
+    }
+
+    let response = with_timeout(CONTROL_RESPONSE_TIMEOUT, responses.next())
+        .await
+        .map_err(|_| ControlError::Failed(opcode))?;
+    Response::parse(response.as_ref(), opcode)
+}
@@ -0,0 +1,61 @@
+//! Host channel classification and the resulting connection channel maps.
+//!
+//! The classification is global to the controller and only shapes the channel
+//! maps of connections in the central role, so the central applies it before
+//! it connects. Per-channel error rates come from the QoS reports.
+
+use bt_hci::cmd::le::{LeReadChannelMap, LeSetHostChannelClassification};
+use bt_hci::controller::ControllerCmdSync;
+use bt_hci::param::ChannelMap;
+use trouble_host::prelude::*;
+
+use crate::config::ChannelMask;
+
+/// Classify every channel outside `mask` as bad
+pub async fn classify<C, P>(stack: &Stack<'_, C, P>, mask: ChannelMask)
+where
+    C: Controller + ControllerCmdSync<LeSetHostChannelClassification>,
+    P: PacketPool,
+{
+    let mut map = ChannelMap::default();
+    for channel in 0..ChannelMask::COUNT {
+        map.set_channel_bad(channel, !mask.is_used(channel));
+    }
+
+    match stack
+        .command(LeSetHostChannelClassification::new(map))
+        .await
+    {
+        Ok(_) => info!(
+            "Host channel classification: {} of {} channels usable",
+            mask.count(),
+            ChannelMask::COUNT
+        ),
+        Err(e) => warn!("Failed to set host channel classification: {:?}", e),
+    }
+}
+
+/// Log the channel map the controller uses on `conn`
+pub async fn log_channel_map<C, P>(stack: &Stack<'_, C, P>, conn: &Connection<'_, P>)
+where
+    C: Controller + ControllerCmdSync<LeReadChannelMap>,
+    P: PacketPool,
+{
+    let map = match stack.command(LeReadChannelMap::new(conn.handle())).await {
+        Ok(ret) => ret.channel_map,
+        Err(e) => {
+            warn!("Failed to read channel map: {:?}", e);
+            return;
+        }
+    };
+
+    let mut used = 0u64;
+    for channel in (0..ChannelMask::COUNT).filter(|&ch| !map.is_channel_bad(ch)) {
+        used |= 1 << channel;
+    }
+    info!(
+        "Channel map: {} channels in use ({:#x})",
+        used.count_ones(),
+        used
+    );
+}
@@ -0,0 +1,238 @@
+//! GATT discovery of the test service with a per-peer handle cache.
+//!
+//! A full discovery costs several ATT round trips on every reconnection. The
+//! handles found are kept per peer address and reused as long as the peer's
+//! Database Hash is unchanged. Peers without a Database Hash are trusted until
+//! they send a Service Changed indication, which drops their entry.
+
+use embassy_time::{Duration, Instant, with_timeout};
+use thiserror::Error;
+use trouble_host::prelude::*;
+
+use super::{CHAR_CMD_UUID, CHAR_CONTROL_POINT_UUID, CHAR_UUID, SERVICE_UUID};
+use crate::control::CONTROL_POINT_LEN;
+
+/// Number of peers whose handles are remembered
+const CACHE_SIZE: usize = 4;
+
+/// Upper bound for a full discovery before the link is given up
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const GATT_SERVICE_UUID: Uuid = Uuid::new_short(0x1801);
+const SERVICE_CHANGED_UUID: Uuid = Uuid::new_short(0x2a05);
+const DATABASE_HASH_UUID: Uuid = Uuid::new_short(0x2b2a);
+
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DiscoveryError {
+    #[error("discovery did not complete within {} ms", DISCOVERY_TIMEOUT.as_millis())]
+    Timeout,
+    #[error("test service not found on peer")]
+    ServiceNotFound,
+    #[error("characteristic `{0}` not found on peer")]
+    CharacteristicNotFound(&'static str),
+    #[error("ATT request failed during discovery")]
+    Gatt,
+}
+
+/// Characteristic handles of the test service on one peer
+#[derive(Clone)]
+pub struct PeerHandles {
+    pub counter: Characteristic<u32>,
+    pub command: Characteristic<u8>,
+    pub control_point: Characteristic<[u8; CONTROL_POINT_LEN]>,
+    pub service_changed: Option<Characteristic<[u8; 4]>>,
+    database_hash: Option<Characteristic<[u8; 16]>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Cache,
+    Full,
+}
+
+pub struct Discovered {
+    pub handles: PeerHandles,
+    pub source: Source,
+    pub elapsed: Duration,
+    /// Time a full discovery took for this peer, when served from cache
+    pub full_discovery: Option<Duration>,
+}
+
+struct CacheEntry {
+    peer: BdAddr,
+    handles: PeerHandles,
+    hash: Option<[u8; 16]>,
+    full_discovery: Duration,
+    last_used: Instant,
+}
+
+pub struct DiscoveryCache {
+    entries: [Option<CacheEntry>; CACHE_SIZE],
+}
+
+impl DiscoveryCache {
+    pub const fn new() -> Self {
+        Self {
+            entries: [const { None }; CACHE_SIZE],
+        }
+    }
+
+    /// Resolve the test service handles, from cache when the peer is unchanged
+    pub async fn discover<C: Controller, P: PacketPool, const MAX_SERVICES: usize>(
+        &mut self,
+        peer: BdAddr,
+        client: &GattClient<'_, C, P, MAX_SERVICES>,
+    ) -> Result<Discovered, DiscoveryError> {
+        let start = Instant::now();
+
+        with_timeout(DISCOVERY_TIMEOUT, async {
+            if let Some(entry) = self.entries.iter_mut().flatten().find(|e| e.peer == peer) {
+                if cache_valid(client, entry).await {
+                    entry.last_used = Instant::now();
+                    return Ok(Discovered {
+                        handles: entry.handles.clone(),
+                        source: Source::Cache,
+                        elapsed: start.elapsed(),
+                        full_discovery: Some(entry.full_discovery),
+                    });
+                }
+            }
+
+            let (handles, hash) = discover_full(client).await?;
+            let elapsed = start.elapsed();
+            self.insert(CacheEntry {
+                peer,
+                handles: handles.clone(),
+                hash,
+                full_discovery: elapsed,
+                last_used: Instant::now(),
+            });
+
+            Ok(Discovered {
+                handles,
+                source: Source::Full,
+                elapsed,
+                full_discovery: None,
+            })
+        })
+        .await
+        .map_err(|_| DiscoveryError::Timeout)?
+    }
+
+    /// Forget the handles of a peer, e.g. after a Service Changed indication
+    pub fn invalidate(&mut self, peer: BdAddr) {
+        for slot in self.entries.iter_mut() {
+            if slot.as_ref().is_some_and(|e| e.peer == peer) {
+                *slot = None;
+            }
+        }
+    }
+
+    fn insert(&mut self, entry: CacheEntry) {
+        self.invalidate(entry.peer);
+
+        // Take a free slot, otherwise evict the least recently used peer
+        let slot = match self.entries.iter().position(Option::is_none) {
+            Some(i) => i,
+            None => self
+                .entries
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, e)| e.as_ref().map(|e| e.last_used))
+                .map(|(i, _)| i)
+                .unwrap_or(0),
+        };
+        self.entries[slot] = Some(entry);
+    }
+}
+
+/// A cached entry is valid if the peer's Database Hash is unchanged or it has none
+async fn cache_valid<C: Controller, P: PacketPool, const MAX_SERVICES: usize>(
+    client: &GattClient<'_, C, P, MAX_SERVICES>,
+    entry: &CacheEntry,
+) -> bool {
+    let (Some(characteristic), Some(cached)) = (&entry.handles.database_hash, entry.hash) else {
+        return true;
+    };
+
+    match read_hash(client, characteristic).await {
+        Some(hash) => hash == cached,
+        None => false,
+    }
+}
+
+async fn read_hash<C: Controller, P: PacketPool, const MAX_SERVICES: usize>(
+    client: &GattClient<'_, C, P, MAX_SERVICES>,
+    characteristic: &Characteristic<[u8; 16]>,
+) -> Option<[u8; 16]> {
+    let mut hash = [0; 16];
+    match client.read_characteristic(characteristic, &mut hash).await {
+        Ok(16) => Some(hash),
+        Ok(len) => {
+            warn!("Database Hash has unexpected length {}", len);
+            None
+        }
+        Err(e) => {
+            warn!("Failed to read Database Hash: {:?}", e);
+            None
+        }
+    }
+}
+
+async fn discover_full<C: Controller, P: PacketPool, const MAX_SERVICES: usize>(
+    client: &GattClient<'_, C, P, MAX_SERVICES>,
+) -> Result<(PeerHandles, Option<[u8; 16]>), DiscoveryError> {
+    let services = client.services_by_uuid(&SERVICE_UUID).await.map_err(|e| {
+        warn!("Service discovery failed: {:?}", e);
+        DiscoveryError::Gatt
+    })?;
+    let service = services.first().ok_or(DiscoveryError::ServiceNotFound)?;
+
+    let counter = client
+        .characteristic_by_uuid::<u32>(service, &CHAR_UUID)
+        .await
+        .map_err(|_| DiscoveryError::CharacteristicNotFound("counter"))?;
+    let command = client
+        .characteristic_by_uuid::<u8>(service, &CHAR_CMD_UUID)
+        .await
+        .map_err(|_| DiscoveryError::CharacteristicNotFound("command"))?;
+    let control_point = client
+        .characteristic_by_uuid::<[u8; CONTROL_POINT_LEN]>(service, &CHAR_CONTROL_POINT_UUID)
+        .await
+        .map_err(|_| DiscoveryError::CharacteristicNotFound("control point"))?;
+
+    // The GATT service is optional, without it the cache is trusted until the link fails
+    let (service_changed, database_hash) = match client.services_by_uuid(&GATT_SERVICE_UUID).await {
+        Ok(services) => match services.first() {
+            Some(gatt) => (
+                client
+                    .characteristic_by_uuid::<[u8; 4]>(gatt, &SERVICE_CHANGED_UUID)
+                    .await
+                    .ok(),
+                client
+                    .characteristic_by_uuid::<[u8; 16]>(gatt, &DATABASE_HASH_UUID)
+                    .await
+                    .ok(),
+            ),
+            None => (None, None),
+        },
+        Err(_) => (None, None),
+    };
+
+    let hash = match &database_hash {
+        Some(characteristic) => read_hash(client, characteristic).await,
+        None => None,
+    };
+
+    Ok((
+        PeerHandles {
+            counter,
+            command,
+            control_point,
+            service_changed,
+            database_hash,
+        },
+        hash,
+    ))
+}
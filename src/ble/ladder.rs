@@ -0,0 +1,240 @@
+//! Fallback ladder for a rejected connection rate request.
+//!
+//! [`negotiate`] requests [`CONN_RATE_PARAMS`] and waits for the controller to
+//! report the interval. The HCI status of a failed request decides what comes
+//! next: a busy controller or a procedure collision gets the same parameters
+//! again once the link is quiet, a rejection of the parameters moves one rung
+//! down the ladder, and a peer without the feature ends the negotiation. A
+//! request that goes out but never shows up counts as a rejection by the peer.
+//! The rungs relax the CE length to anything up to the interval, then let the
+//! subrate factor go down to 1, then step to each longer interval in the
+//! controller's supported table. Without that table the ladder stops there
+//! with [`Failure::NoIntervalTable`]. Every attempt is kept with the reason it
+//! failed and logged with the parameters finally agreed. The controller may
+//! settle on any interval between the requested minimum and maximum.
+
+use embassy_time::{Duration, Timer};
+use trouble_host::prelude::*;
+
+use super::bringup::{RETRY_PAUSE, wait_for};
+use super::{CONN_RATE_PARAMS, SciController};
+use crate::config::{BringUp, TEST_CONFIG};
+use crate::intervals;
+use crate::watchdog::{self, Task};
+
+const MAX_ATTEMPTS: usize = 10;
+
+/// How an attempt's parameters differ from the previous rung
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Rung {
+    Target,
+    /// Any CE length up to the interval
+    CeLength,
+    /// Subrate factors down to 1
+    Subrate,
+    /// The next longer supported interval
+    Interval,
+}
+
+/// Why an attempt didn't end at its parameters, with the HCI status where there was one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Failure {
+    /// Command disallowed, procedure collision or controller busy, worth another try
+    Busy(u8),
+    /// The parameters are out of what either controller accepts
+    Rejected(u8),
+    /// The peer doesn't support the procedure
+    Unsupported(u8),
+    /// Any other status, e.g. an LL response timeout
+    Other(u8),
+    /// The host failed the request without an HCI status
+    Host,
+    /// Sent, but the controller never reported the interval
+    NotApplied,
+    /// Longer intervals are unknown, the controller's table wasn't read
+    NoIntervalTable,
+}
+
+impl Failure {
+    fn from_status(status: u8) -> Self {
+        match status {
+            // Command disallowed, LL procedure collision, different transaction collision, controller busy
+            0x0C | 0x23 | 0x2A | 0x3A => Failure::Busy(status),
+            // Unsupported feature or parameter value, invalid HCI or LL parameters,
+            // unsupported LL parameter value, unacceptable connection parameters
+            0x11 | 0x12 | 0x1E | 0x20 | 0x3B => Failure::Rejected(status),
+            // Unsupported remote feature
+            0x1A => Failure::Unsupported(status),
+            _ => Failure::Other(status),
+        }
+    }
+
+    fn from_error<E>(e: &BleHostError<E>) -> Self {
+        match e {
+            BleHostError::BleHost(Error::Hci(e)) => Self::from_status(e.to_status().into_inner()),
+            _ => Failure::Host,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Attempt {
+    rung: Rung,
+    params: ConnectRateParams,
+    failure: Option<Failure>,
+}
+
+/// Every attempt of one negotiation
+pub struct Negotiation {
+    attempts: [Option<Attempt>; MAX_ATTEMPTS],
+    /// Why the ladder ran out of rungs early, if it did
+    stopped: Option<Failure>,
+}
+
+impl Negotiation {
+    /// Parameters of the attempt that was applied, if any was
+    pub fn agreed(&self) -> Option<ConnectRateParams> {
+        self.attempts
+            .iter()
+            .flatten()
+            .find(|a| a.failure.is_none())
+            .map(|a| a.params)
+    }
+
+    pub fn log(&self) {
+        match self.agreed() {
+            Some(p) => info!(
+                "Connection rate agreed: interval={}us, subrate={}-{}, latency={}, cont={}, ce={}-{}us",
+                p.min_connection_interval.as_micros(),
+                p.subrate_min,
+                p.subrate_max,
+                p.max_latency,
+                p.continuation_number,
+                p.min_ce_length.as_micros(),
+                p.max_ce_length.as_micros()
+            ),
+            None => warn!("No connection rate agreed, the link stays at its current rate"),
+        }
+        if let Some(failure) = self.stopped {
+            warn!("  ladder stopped: {:?}", failure);
+        }
+        for (i, a) in self.attempts.iter().flatten().enumerate() {
+            let Some(failure) = a.failure else {
+                continue;
+            };
+            info!(
+                "  attempt {} ({:?}): interval={}us, subrate={}-{}, ce={}-{}us failed: {:?}",
+                i + 1,
+                a.rung,
+                a.params.min_connection_interval.as_micros(),
+                a.params.subrate_min,
+                a.params.subrate_max,
+                a.params.min_ce_length.as_micros(),
+                a.params.max_ce_length.as_micros(),
+                failure
+            );
+        }
+    }
+}
+
+/// The rung below `params`, `None` once there is nothing left to relax
+fn relax(params: &ConnectRateParams) -> Result<Option<(Rung, ConnectRateParams)>, Failure> {
+    let interval = params.min_connection_interval;
+    if params.min_ce_length > Duration::from_micros(0) || params.max_ce_length < interval {
+        let relaxed = ConnectRateParams {
+            min_ce_length: Duration::from_micros(0),
+            max_ce_length: interval,
+            ..*params
+        };
+        return Ok(Some((Rung::CeLength, relaxed)));
+    }
+    if params.subrate_min > 1 {
+        let relaxed = ConnectRateParams {
+            subrate_min: 1,
+            ..*params
+        };
+        return Ok(Some((Rung::Subrate, relaxed)));
+    }
+    let supported = intervals::supported().ok_or(Failure::NoIntervalTable)?;
+    let Some(next) = supported.iter().filter(|&i| i > interval).min() else {
+        return Ok(None);
+    };
+    let relaxed = ConnectRateParams {
+        min_connection_interval: next,
+        max_connection_interval: next.max(params.max_connection_interval),
+        max_ce_length: next,
+        ..*params
+    };
+    Ok(Some((Rung::Interval, relaxed)))
+}
+
+/// Request [`CONN_RATE_PARAMS`], falling back down the ladder until one is applied
+pub async fn negotiate<C: SciController>(
+    stack: &Stack<'_, C, DefaultPacketPool>,
+    conn: &Connection<'_, DefaultPacketPool>,
+) -> Negotiation {
+    let mut negotiation = Negotiation {
+        attempts: [None; MAX_ATTEMPTS],
+        stopped: None,
+    };
+    let mut rung = Rung::Target;
+    let mut params = CONN_RATE_PARAMS;
+
+    for slot in negotiation.attempts.iter_mut() {
+        watchdog::check_in(Task::TestLoop);
+        let failure = match conn.request_connection_rate(stack, &params).await {
+            Ok(_) if wait_for_interval(conn, &params).await => None,
+            Ok(_) => Some(Failure::NotApplied),
+            Err(e) => {
+                warn!("Connection rate request failed: {:?}", e);
+                Some(Failure::from_error(&e))
+            }
+        };
+        *slot = Some(Attempt {
+            rung,
+            params,
+            failure,
+        });
+
+        match failure {
+            None => break,
+            Some(Failure::Busy(_) | Failure::Other(_) | Failure::Host) => {
+                match TEST_CONFIG.bring_up {
+                    BringUp::Serial => Timer::after(RETRY_PAUSE).await,
+                    // Whatever procedure is in the way ends with an event
+                    BringUp::Overlapped => {
+                        wait_for(conn, |_| true).await;
+                    }
+                }
+            }
+            Some(Failure::Rejected(_) | Failure::NotApplied) => match relax(&params) {
+                Ok(Some((next_rung, next))) => (rung, params) = (next_rung, next),
+                Ok(None) => break,
+                Err(failure) => {
+                    negotiation.stopped = Some(failure);
+                    break;
+                }
+            },
+            Some(Failure::Unsupported(_) | Failure::NoIntervalTable) => break,
+        }
+    }
+    negotiation
+}
+
+/// Wait for the controller to report an interval within the requested range
+async fn wait_for_interval(
+    conn: &Connection<'_, DefaultPacketPool>,
+    params: &ConnectRateParams,
+) -> bool {
+    let requested = params.min_connection_interval..=params.max_connection_interval;
+    wait_for(conn, |e| {
+        matches!(
+            e,
+            ConnectionEvent::ConnectionParamsUpdated { conn_interval, .. }
+                if requested.contains(conn_interval)
+        )
+    })
+    .await
+}
@@ -0,0 +1,562 @@
+use core::future::pending;
+
+use embassy_futures::{
+    join::join,
+    select::{Either4, select4},
+};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use static_cell::StaticCell;
+use trouble_host::gatt::GattConnectionEvent;
+use trouble_host::prelude::*;
+
+use super::policy::{RateGuard, Source, Verdict};
+use super::{
+    ADVERTISE_NAME, ECHO_FLAG, ORIGINATED_FLAG, SERVICE_UUID_BYTES, SciController,
+    connect_rate_params, power, qos, read_supported_intervals, run_runner, set_host_features,
+};
+use crate::config::{PingPrimitives, PingRequest, PingResponse, TEST_CONFIG, TestMode};
+use crate::control::{
+    CONTROL_POINT_LEN, Command, ControlError, IntervalPage, Response, RttDistribution, StatsSummary,
+};
+use crate::crash;
+use crate::gatt::CounterServer;
+use crate::intervals;
+use crate::random::Xorshift32;
+use crate::stats::Stats;
+use crate::watchdog::{self, Task};
+
+/// How often the results service is refreshed
+const RESULTS_PERIOD: Duration = Duration::from_secs(1);
+
+/// Next originated send when the central's echo never arrives
+const ECHO_TIMEOUT: Duration = Duration::from_secs(1);
+
+static SERVER: StaticCell<CounterServer<'static>> = StaticCell::new();
+
+/// Test state driven by the control point
+struct TestSession {
+    counter: u32,
+    mode: TestMode,
+    ping: PingPrimitives,
+    running: bool,
+    stats: Stats,
+    /// When the last ping or pong left, cleared once the central answered it
+    last_send: Option<Instant>,
+    last_seq: Option<u8>,
+    /// First ping still to be sent in [`TestMode::PeripheralPing`]
+    kickoff: bool,
+    /// Notifications per trigger in [`TestMode::Burst`]
+    burst: u8,
+    rng: Xorshift32,
+    /// When to originate data next in [`TestMode::PeripheralLatency`]
+    next_spontaneous: Option<Instant>,
+    rate_guard: Option<RateGuard>,
+}
+
+impl TestSession {
+    fn new() -> Self {
+        Self {
+            counter: 0,
+            mode: TestMode::PingPong,
+            ping: PingPrimitives::DEFAULT,
+            running: true,
+            stats: Stats::new(),
+            last_send: None,
+            last_seq: None,
+            kickoff: false,
+            burst: 1,
+            rng: Xorshift32::from_uptime(),
+            next_spontaneous: None,
+            rate_guard: match TEST_CONFIG.rate_policy {
+                Some(policy) => Some(RateGuard::new(policy)),
+                None => None,
+            },
+        }
+    }
+
+    /// Account for a ping or echo, written ones carry the central's sequence number
+    fn on_ping(&mut self, seq: Option<u8>) {
+        if let Some(sent) = self.last_send.take() {
+            self.stats.rtt.record(sent.elapsed());
+        }
+        if let (Some(last), Some(seq)) = (self.last_seq, seq) {
+            let gap = seq.wrapping_sub(last).wrapping_sub(1);
+            self.stats.packets.lost += gap as u32;
+        }
+        self.last_seq = seq;
+        self.stats.packets.received += 1;
+    }
+
+    /// Send the counter to the central and remember when it left
+    async fn send_ping<P: PacketPool>(
+        &mut self,
+        server: &CounterServer<'_>,
+        conn: &GattConnection<'_, '_, P>,
+    ) {
+        server
+            .counter_service
+            .counter
+            .set(server, &self.counter)
+            .unwrap();
+
+        self.last_send = Some(Instant::now());
+        match send_counter(server, conn, self.counter, self.ping.response).await {
+            Ok(_) => self.stats.packets.sent += 1,
+            Err(_) => {
+                self.last_send = None;
+                self.stats.packets.lost += 1;
+            }
+        }
+        self.counter = self.counter.wrapping_add(1);
+    }
+
+    /// Queue the burst's notifications back to back
+    async fn send_burst<P: PacketPool>(
+        &mut self,
+        server: &CounterServer<'_>,
+        conn: &GattConnection<'_, '_, P>,
+    ) {
+        for _ in 0..self.burst {
+            self.send_ping(server, conn).await;
+        }
+        // The central times the burst, the next trigger answers no single send
+        self.last_send = None;
+    }
+
+    /// Originate flagged data and wait for the central's echo
+    async fn send_spontaneous<P: PacketPool>(
+        &mut self,
+        server: &CounterServer<'_>,
+        conn: &GattConnection<'_, '_, P>,
+    ) {
+        let value = (self.counter & !ORIGINATED_FLAG) | ORIGINATED_FLAG;
+        self.last_send = Some(Instant::now());
+        match send_counter(server, conn, value, self.ping.response).await {
+            Ok(_) => self.stats.packets.sent += 1,
+            Err(_) => {
+                self.last_send = None;
+                self.stats.packets.lost += 1;
+            }
+        }
+        self.counter = self.counter.wrapping_add(1);
+        self.next_spontaneous = Some(Instant::now() + ECHO_TIMEOUT);
+    }
+
+    /// Answer central-originated data straight away, leaving the originated round trip alone
+    async fn send_pong<P: PacketPool>(
+        &mut self,
+        server: &CounterServer<'_>,
+        conn: &GattConnection<'_, '_, P>,
+    ) {
+        self.stats.packets.received += 1;
+        let value = self.counter & !ORIGINATED_FLAG;
+        match send_counter(server, conn, value, self.ping.response).await {
+            Ok(_) => self.stats.packets.sent += 1,
+            Err(_) => self.stats.packets.lost += 1,
+        }
+        self.counter = self.counter.wrapping_add(1);
+    }
+
+    fn schedule_spontaneous(&mut self) {
+        let max = Duration::from_millis(TEST_CONFIG.wake_up.max_gap_ms as u64);
+        self.next_spontaneous = Some(Instant::now() + self.rng.pause(max));
+    }
+
+    fn reset(&mut self) {
+        self.counter = 0;
+        self.stats.reset();
+        self.last_send = None;
+        self.last_seq = None;
+    }
+
+    async fn execute<C: SciController>(
+        &mut self,
+        command: Command,
+        stack: &Stack<'_, C, DefaultPacketPool>,
+        conn: &Connection<'_, DefaultPacketPool>,
+    ) -> Result<Response, ControlError> {
+        let opcode = command.opcode();
+        match command {
+            Command::ResetCounters => self.reset(),
+            Command::StartTest if self.running => return Err(ControlError::InvalidState(opcode)),
+            Command::StartTest if !self.ping.supports(self.mode) => {
+                return Err(ControlError::InvalidState(opcode));
+            }
+            Command::StartTest => {
+                self.running = true;
+                self.kickoff = self.mode == TestMode::PeripheralPing;
+                if self.mode == TestMode::PeripheralLatency {
+                    self.schedule_spontaneous();
+                }
+            }
+            Command::StopTest if !self.running => return Err(ControlError::InvalidState(opcode)),
+            Command::StopTest => {
+                self.running = false;
+                self.next_spontaneous = None;
+            }
+            Command::SelectMode(_) if self.running => {
+                return Err(ControlError::InvalidState(opcode));
+            }
+            Command::SelectMode(mode) => self.mode = mode,
+            Command::SelectPrimitives(_) if self.running => {
+                return Err(ControlError::InvalidState(opcode));
+            }
+            Command::SelectPrimitives(ping) => self.ping = ping,
+            Command::SetBurstSize(_) if self.running => {
+                return Err(ControlError::InvalidState(opcode));
+            }
+            Command::SetBurstSize(n) => self.burst = n,
+            // defmt filters at build time
+            #[cfg(not(feature = "log"))]
+            Command::SetLogLevel(_) => return Err(ControlError::Failed(opcode)),
+            #[cfg(feature = "log")]
+            Command::SetLogLevel(level) => {
+                crate::logger::set_level(level);
+                info!("Log level set to {:?}", level);
+            }
+            Command::SetTxPower(dbm) => match power::set_tx_power(stack, conn, dbm).await {
+                Ok(selected) => {
+                    info!("TX power set to {} dBm ({} dBm requested)", selected, dbm);
+                    self.stats.signal.tx_power_dbm = Some(selected);
+                    return Ok(Response::tx_power(selected));
+                }
+                Err(e) => {
+                    warn!("Failed to set TX power: {:?}", e);
+                    return Err(ControlError::Failed(opcode));
+                }
+            },
+            Command::RequestConnectionRate(requested) => {
+                let requested = connect_rate_params(&requested);
+                let verdict = self
+                    .rate_guard
+                    .as_mut()
+                    .map(|guard| guard.judge(Source::ControlPoint, &requested));
+                let params = match verdict {
+                    None | Some(Verdict::Accept) => requested,
+                    Some(Verdict::Counter(countered)) => countered,
+                    Some(Verdict::Reject(_)) => return Err(ControlError::InvalidParameter(opcode)),
+                };
+                if let Err(e) = conn.request_connection_rate(stack, &params).await {
+                    warn!("Peer-side connection rate request failed: {:?}", e);
+                    return Err(ControlError::Failed(opcode));
+                }
+            }
+            Command::FetchStats => {
+                let rtt = self.stats.rtt.summary();
+                return Ok(Response::stats(&StatsSummary {
+                    pings: self.counter,
+                    mode: self.mode,
+                    running: self.running,
+                    rtt_mean_us: rtt.mean_us,
+                    rtt_p99_us: rtt.p99_us,
+                }));
+            }
+            Command::FetchRtt => {
+                let rtt = self.stats.rtt.summary();
+                return Ok(Response::rtt(&RttDistribution {
+                    count: rtt.count,
+                    p50_us: rtt.p50_us,
+                    p99_us: rtt.p99_us,
+                    max_us: rtt.max_us,
+                }));
+            }
+            Command::FetchIntervals(index) => {
+                let Some(supported) = intervals::supported() else {
+                    return Err(ControlError::Failed(opcode));
+                };
+                let groups = supported.groups();
+                let group = groups
+                    .get(index as usize)
+                    .ok_or(ControlError::InvalidParameter(opcode))?;
+                return Ok(Response::intervals(&IntervalPage {
+                    minimum: supported.minimum as u8,
+                    num_groups: groups.len() as u8,
+                    group: *group,
+                }));
+            }
+        }
+        Ok(Response::success(opcode))
+    }
+}
+
+/// Send the counter to the central with the selected response primitive
+async fn send_counter<P: PacketPool>(
+    server: &CounterServer<'_>,
+    conn: &GattConnection<'_, '_, P>,
+    counter: u32,
+    response: PingResponse,
+) -> Result<(), Error> {
+    let characteristic = &server.counter_service.counter;
+    match response {
+        PingResponse::Notification => characteristic.notify(conn, &counter).await,
+        PingResponse::Indication => characteristic.indicate(conn, &counter).await,
+    }
+}
+
+/// Refresh the results service values and notify subscribers
+async fn publish_results<P: PacketPool>(
+    server: &CounterServer<'_>,
+    conn: &GattConnection<'_, '_, P>,
+    stats: &Stats,
+) {
+    let results = &server.results_service;
+    let link = stats.link.encode();
+    let rtt = stats.rtt.summary().encode();
+    let packets = stats.packets.encode();
+    let uptime = Instant::now().as_secs() as u32;
+    let quality = qos::QOS.latest().unwrap_or_default().encode();
+    let signal = stats.signal.encode();
+
+    let _ = results.link_params.notify(conn, &link).await;
+    let _ = results.rtt.notify(conn, &rtt).await;
+    let _ = results.packets.notify(conn, &packets).await;
+    let _ = results.uptime.notify(conn, &uptime).await;
+    let _ = results.link_quality.notify(conn, &quality).await;
+    let _ = results.signal.notify(conn, &signal).await;
+}
+
+pub(super) async fn run<C: SciController>(stack: &Stack<'_, C, DefaultPacketPool>) {
+    let Host {
+        mut peripheral,
+        mut runner,
+        ..
+    } = stack.build();
+
+    let server = SERVER.init(
+        CounterServer::new_with_config(GapConfig::Peripheral(PeripheralConfig {
+            name: ADVERTISE_NAME,
+            appearance: &appearance::power_device::GENERIC_POWER_DEVICE,
+        }))
+        .unwrap(),
+    );
+    server
+        .results_service
+        .crash
+        .set(server, &crash::boot_report().encode())
+        .unwrap();
+
+    join(run_runner(&mut runner, stack), async {
+        // Enable host features for Connection Subrating and Shorter Connection Intervals
+        set_host_features(stack).await;
+        qos::enable(stack).await;
+        // Reported to the central, which solves latency budgets against both tables
+        read_supported_intervals(stack).await;
+
+        let mut adv_data = [0; 31];
+        let mut scan_data = [0; 31];
+
+        let len_adv = AdStructure::encode_slice(
+            &[
+                AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+                AdStructure::ServiceUuids128(&[SERVICE_UUID_BYTES]),
+            ],
+            &mut adv_data,
+        )
+        .unwrap();
+
+        let len_scan = AdStructure::encode_slice(
+            &[AdStructure::CompleteLocalName(ADVERTISE_NAME.as_bytes())],
+            &mut scan_data,
+        )
+        .unwrap();
+
+        loop {
+            info!("Advertising...");
+            watchdog::check_in(Task::TestLoop);
+
+            let advertiser = peripheral
+                .advertise(
+                    &Default::default(),
+                    Advertisement::ConnectableScannableUndirected {
+                        adv_data: &adv_data[..len_adv],
+                        scan_data: &scan_data[..len_scan],
+                    },
+                )
+                .await
+                .unwrap();
+
+            let connection = match watchdog::waiting(Task::TestLoop, advertiser.accept()).await {
+                Ok(conn) => conn,
+                Err(_) => continue,
+            };
+
+            qos::QOS.reset();
+            let mut session = TestSession::new();
+            let params = connection.params();
+            let link = &mut session.stats.link;
+            link.interval = params.conn_interval;
+            link.peripheral_latency = params.peripheral_latency;
+            link.supervision_timeout = params.supervision_timeout;
+            // Legacy advertising connects on LE 1M, later changes come as PHY updates
+            link.tx_phy = PhyKind::Le1M as u8;
+            link.rx_phy = PhyKind::Le1M as u8;
+            let gatt_conn = connection.with_attribute_server(server).unwrap();
+
+            let mut results_ticker = Ticker::every(RESULTS_PERIOD);
+            let mut rssi_ticker = Ticker::every(power::RSSI_PERIOD);
+
+            loop {
+                watchdog::check_in(Task::TestLoop);
+                let next_spontaneous = session.next_spontaneous;
+                let spontaneous = async {
+                    match next_spontaneous {
+                        Some(at) => Timer::at(at).await,
+                        None => pending().await,
+                    }
+                };
+                let event = match select4(
+                    gatt_conn.next(),
+                    results_ticker.next(),
+                    rssi_ticker.next(),
+                    spontaneous,
+                )
+                .await
+                {
+                    Either4::First(event) => event,
+                    Either4::Second(_) => {
+                        // The host stack doesn't forward subrate changes, the QoS reports show them
+                        if let Some(quality) = qos::QOS.latest() {
+                            session.stats.link.subrate_factor = quality.subrate_factor();
+                        }
+                        publish_results(server, &gatt_conn, &session.stats).await;
+                        session.stats.signal.start_window();
+                        continue;
+                    }
+                    Either4::Third(_) => {
+                        if let Ok(rssi) = power::read_rssi(stack, gatt_conn.raw()).await {
+                            session.stats.signal.record_rssi(rssi);
+                        }
+                        continue;
+                    }
+                    Either4::Fourth(_) => {
+                        session.send_spontaneous(server, &gatt_conn).await;
+                        continue;
+                    }
+                };
+
+                match event {
+                    GattConnectionEvent::Disconnected { .. } => {
+                        qos::QOS.reset();
+                        break;
+                    }
+                    GattConnectionEvent::ConnectionParamsUpdated {
+                        conn_interval,
+                        peripheral_latency,
+                        supervision_timeout,
+                    } => {
+                        let link = &mut session.stats.link;
+                        link.interval = conn_interval;
+                        link.peripheral_latency = peripheral_latency;
+                        link.supervision_timeout = supervision_timeout;
+
+                        if let Some(guard) = &mut session.rate_guard {
+                            let applied = guard.applied(
+                                conn_interval,
+                                peripheral_latency,
+                                supervision_timeout,
+                            );
+                            if let Verdict::Reject(Some(params)) | Verdict::Counter(params) =
+                                guard.judge(Source::Applied, &applied)
+                                && let Err(e) = gatt_conn
+                                    .raw()
+                                    .request_connection_rate(stack, &params)
+                                    .await
+                            {
+                                warn!("Failed to push back on the connection rate: {:?}", e);
+                            }
+                        }
+                    }
+                    GattConnectionEvent::PhyUpdated { tx_phy, rx_phy } => {
+                        session.stats.link.tx_phy = tx_phy as u8;
+                        session.stats.link.rx_phy = rx_phy as u8;
+                    }
+                    GattConnectionEvent::Gatt { event } => match event {
+                        GattEvent::Write(write)
+                            if write.handle() == server.counter_service.control_point.handle =>
+                        {
+                            // Copy the request out so the write response goes out before the indication
+                            let mut request = [0; CONTROL_POINT_LEN];
+                            let len = write.data().len().min(CONTROL_POINT_LEN);
+                            request[..len].copy_from_slice(&write.data()[..len]);
+
+                            match write.accept() {
+                                Ok(reply) => reply.send().await,
+                                Err(e) => warn!("Failed to accept control-point write: {:?}", e),
+                            }
+
+                            // The central's answer will be delayed by this request, don't sample it
+                            session.last_send = None;
+
+                            let response = match Command::parse(&request[..len]) {
+                                Ok(command) => {
+                                    session.execute(command, stack, gatt_conn.raw()).await
+                                }
+                                Err(e) => Err(e),
+                            }
+                            .unwrap_or_else(|e| {
+                                warn!("Control-point request rejected: {}", e);
+                                Response::error(&e)
+                            });
+
+                            if let Err(e) = server
+                                .counter_service
+                                .control_point
+                                .indicate(&gatt_conn, response.value())
+                                .await
+                            {
+                                warn!("Failed to indicate control-point response: {:?}", e);
+                            }
+
+                            if core::mem::take(&mut session.kickoff) {
+                                session.send_ping(server, &gatt_conn).await;
+                            }
+                        }
+                        GattEvent::Write(write)
+                            if write.handle() == server.counter_service.command.handle =>
+                        {
+                            if !session.running || session.ping.request == PingRequest::Read {
+                                continue;
+                            }
+                            let seq = write.data().first().copied();
+                            if session.mode == TestMode::PeripheralLatency {
+                                if seq.is_some_and(|s| s & ECHO_FLAG != 0) {
+                                    session.on_ping(None);
+                                    session.schedule_spontaneous();
+                                } else {
+                                    session.send_pong(server, &gatt_conn).await;
+                                }
+                                continue;
+                            }
+                            session.on_ping(seq);
+                            if session.mode == TestMode::Burst {
+                                session.send_burst(server, &gatt_conn).await;
+                            } else {
+                                session.send_ping(server, &gatt_conn).await;
+                            }
+                        }
+                        GattEvent::Read(read)
+                            if read.handle() == server.counter_service.counter.handle =>
+                        {
+                            if !session.running || session.ping.request != PingRequest::Read {
+                                continue;
+                            }
+                            session.on_ping(None);
+
+                            // Update the value before the read is answered so every poll sees a new count
+                            session.counter = session.counter.wrapping_add(1);
+                            server
+                                .counter_service
+                                .counter
+                                .set(&server, &session.counter)
+                                .unwrap();
+                            session.stats.packets.sent += 1;
+                        }
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+        }
+    })
+    .await;
+}
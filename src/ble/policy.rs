@@ -0,0 +1,178 @@
+//! Peripheral-side policy for the connection rates it is handed.
+//!
+//! The peripheral's host only learns of a rate the central applied once the
+//! controller reports the new interval and latency, so [`RateGuard`] judges
+//! those afterwards and pushes back with a connection rate request of its own:
+//! the last rate it accepted for [`Pushback::Reject`], the closest rate within
+//! the [`RatePolicy`] for [`Pushback::CounterPropose`]. Rates the central asks
+//! it to request through the control point are judged before they go out.
+//! Subrate changes aren't reported to the host, so only those requests have
+//! their subrate factor checked. Every request is logged with its outcome.
+
+use embassy_time::Duration;
+use trouble_host::prelude::ConnectRateParams;
+
+use super::CONN_RATE_PARAMS;
+use crate::config::{Pushback, RatePolicy};
+
+const SUPERVISION_TIMEOUT_MAX_US: u64 = 32_000_000;
+const SUPERVISION_TIMEOUT_UNIT_US: u64 = 10_000;
+
+/// Where a rate came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Source {
+    /// The central asked the peripheral to request it
+    ControlPoint,
+    /// The controller reported it in effect
+    Applied,
+}
+
+/// The first bound a rate breaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Violation {
+    IntervalTooShort,
+    IntervalTooLong,
+    SubrateTooHigh,
+    LatencyTooHigh,
+}
+
+/// What to do about a rate
+#[derive(Debug, Clone, Copy)]
+pub enum Verdict {
+    Accept,
+    /// Keep the last accepted rate, if there was one
+    Reject(Option<ConnectRateParams>),
+    /// Request these instead
+    Counter(ConnectRateParams),
+}
+
+/// Judges every rate of one connection against the [`RatePolicy`]
+pub struct RateGuard {
+    policy: RatePolicy,
+    /// Last rate within bounds, subrate and CE length as last requested
+    accepted: Option<ConnectRateParams>,
+}
+
+impl RateGuard {
+    pub const fn new(policy: RatePolicy) -> Self {
+        Self {
+            policy,
+            accepted: None,
+        }
+    }
+
+    /// The rate the controller reported, with the rest as last accepted
+    pub fn applied(
+        &self,
+        interval: Duration,
+        latency: u16,
+        timeout: Duration,
+    ) -> ConnectRateParams {
+        ConnectRateParams {
+            min_connection_interval: interval,
+            max_connection_interval: interval,
+            max_latency: latency,
+            supervision_timeout: timeout,
+            ..self.accepted.unwrap_or(CONN_RATE_PARAMS)
+        }
+    }
+
+    pub fn judge(&mut self, source: Source, params: &ConnectRateParams) -> Verdict {
+        let violation = self.check(source, params);
+        let verdict = match (violation, self.policy.pushback) {
+            (None, _) => {
+                self.accepted = Some(*params);
+                Verdict::Accept
+            }
+            (Some(_), Pushback::Reject) => Verdict::Reject(self.accepted),
+            (Some(_), Pushback::CounterPropose) => Verdict::Counter(self.counter(params)),
+        };
+
+        info!(
+            "Rate {:?}: interval {}-{}us, subrate {}-{}, latency {}",
+            source,
+            params.min_connection_interval.as_micros(),
+            params.max_connection_interval.as_micros(),
+            params.subrate_min,
+            params.subrate_max,
+            params.max_latency
+        );
+        match (violation, verdict) {
+            (_, Verdict::Accept) => info!("  accepted"),
+            (Some(v), Verdict::Reject(Some(p))) => info!(
+                "  rejected ({:?}), back to {}us latency {}",
+                v,
+                p.min_connection_interval.as_micros(),
+                p.max_latency
+            ),
+            (Some(v), Verdict::Reject(None)) => {
+                warn!("  rejected ({:?}), no earlier rate to go back to", v)
+            }
+            (Some(v), Verdict::Counter(p)) => info!(
+                "  countered ({:?}) with interval {}-{}us, subrate {}-{}, latency {}",
+                v,
+                p.min_connection_interval.as_micros(),
+                p.max_connection_interval.as_micros(),
+                p.subrate_min,
+                p.subrate_max,
+                p.max_latency
+            ),
+            (None, _) => {}
+        }
+        verdict
+    }
+
+    fn check(&self, source: Source, params: &ConnectRateParams) -> Option<Violation> {
+        let policy = &self.policy;
+        if params.min_connection_interval < us(policy.min_interval_us) {
+            Some(Violation::IntervalTooShort)
+        } else if params.max_connection_interval > us(policy.max_interval_us) {
+            Some(Violation::IntervalTooLong)
+        } else if source == Source::ControlPoint && params.subrate_max > policy.max_subrate {
+            Some(Violation::SubrateTooHigh)
+        } else if params.max_latency > policy.max_latency {
+            Some(Violation::LatencyTooHigh)
+        } else {
+            None
+        }
+    }
+
+    /// `params` clamped into the policy, the rest adjusted to stay valid
+    fn counter(&self, params: &ConnectRateParams) -> ConnectRateParams {
+        let policy = &self.policy;
+        let (min, max) = (us(policy.min_interval_us), us(policy.max_interval_us));
+        let min_interval = params.min_connection_interval.clamp(min, max);
+        let max_interval = params.max_connection_interval.clamp(min_interval, max);
+        let subrate_max = params.subrate_max.min(policy.max_subrate);
+        let latency = params.max_latency.min(policy.max_latency);
+        let continuation = params
+            .continuation_number
+            .min(subrate_max.saturating_sub(1));
+        let max_ce = params.max_ce_length.min(min_interval);
+
+        // Must exceed twice the longest time the peripheral may stay away
+        let away_us = 2 * max_interval.as_micros() * subrate_max as u64 * (latency as u64 + 1);
+        let timeout_us = params
+            .supervision_timeout
+            .as_micros()
+            .max((away_us / SUPERVISION_TIMEOUT_UNIT_US + 1) * SUPERVISION_TIMEOUT_UNIT_US);
+
+        ConnectRateParams {
+            min_connection_interval: min_interval,
+            max_connection_interval: max_interval,
+            subrate_min: params.subrate_min.min(subrate_max),
+            subrate_max,
+            max_latency: latency,
+            continuation_number: continuation,
+            supervision_timeout: Duration::from_micros(timeout_us.min(SUPERVISION_TIMEOUT_MAX_US)),
+            min_ce_length: params.min_ce_length.min(max_ce),
+            max_ce_length: max_ce,
+        }
+    }
+}
+
+fn us(us: u32) -> Duration {
+    Duration::from_micros(us as u64)
+}
@@ -0,0 +1,113 @@
+//! Per-connection TX power, RSSI sampling and path-loss zones.
+//!
+//! TX power is set per connection with the Zephyr vendor command the SDC
+//! implements. The host stack does not forward LE Power Control's Path Loss
+//! Threshold events, so [`PathLossMonitor`] applies the same zone rules on the
+//! host, to the path loss between the peer's selected TX power and our RSSI.
+
+use bt_hci::cmd::status::ReadRssi;
+use bt_hci::controller::ControllerCmdSync;
+use embassy_time::Duration;
+use nrf_sdc::vendor::ZephyrWriteTxPower;
+use trouble_host::prelude::*;
+
+use crate::config::PathLossZones;
+
+/// Interval between RSSI samples while connected
+pub const RSSI_PERIOD: Duration = Duration::from_millis(100);
+
+/// `handle_type` of Zephyr Write TX Power addressing a connection handle
+const TX_POWER_HANDLE_CONN: u8 = 0x02;
+
+/// Set the TX power of one connection, returns the level the controller selected
+pub async fn set_tx_power<C, P>(
+    stack: &Stack<'_, C, P>,
+    conn: &Connection<'_, P>,
+    dbm: i8,
+) -> Result<i8, BleHostError<C::Error>>
+where
+    C: Controller + ControllerCmdSync<ZephyrWriteTxPower>,
+    P: PacketPool,
+{
+    let ret = stack
+        .command(ZephyrWriteTxPower::new(
+            TX_POWER_HANDLE_CONN,
+            conn.handle().raw(),
+            dbm,
+        ))
+        .await?;
+    Ok(ret.selected_tx_power)
+}
+
+pub async fn read_rssi<C, P>(
+    stack: &Stack<'_, C, P>,
+    conn: &Connection<'_, P>,
+) -> Result<i8, BleHostError<C::Error>>
+where
+    C: Controller + ControllerCmdSync<ReadRssi>,
+    P: PacketPool,
+{
+    Ok(stack.command(ReadRssi::new(conn.handle())).await?.rssi)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PathLossZone {
+    Low,
+    Middle,
+    High,
+}
+
+/// Path-loss zone tracking with LE Power Control hysteresis
+pub struct PathLossMonitor {
+    zones: PathLossZones,
+    /// TX power the peer selected, path loss is measured against it
+    peer_tx_power_dbm: i8,
+    zone: Option<PathLossZone>,
+}
+
+impl PathLossMonitor {
+    pub fn new(zones: PathLossZones, peer_tx_power_dbm: i8) -> Self {
+        Self {
+            zones,
+            peer_tx_power_dbm,
+            zone: None,
+        }
+    }
+
+    pub fn path_loss(&self, rssi: i8) -> u8 {
+        (self.peer_tx_power_dbm as i16 - rssi as i16).clamp(0, u8::MAX as i16) as u8
+    }
+
+    pub fn zone(&self) -> Option<PathLossZone> {
+        self.zone
+    }
+
+    /// Feed one RSSI sample, returns the zone if it changed
+    pub fn update(&mut self, rssi: i8) -> Option<PathLossZone> {
+        let path_loss = self.path_loss(rssi);
+        let z = &self.zones;
+        let above_high = path_loss > z.high_threshold.saturating_add(z.high_hysteresis);
+        let below_high = path_loss < z.high_threshold.saturating_sub(z.high_hysteresis);
+        let above_low = path_loss > z.low_threshold.saturating_add(z.low_hysteresis);
+        let below_low = path_loss < z.low_threshold.saturating_sub(z.low_hysteresis);
+
+        let zone = match self.zone {
+            // The first zone is picked without hysteresis
+            None if path_loss >= z.high_threshold => PathLossZone::High,
+            None if path_loss < z.low_threshold => PathLossZone::Low,
+            None => PathLossZone::Middle,
+            Some(PathLossZone::High) if below_high && below_low => PathLossZone::Low,
+            Some(PathLossZone::High) if below_high => PathLossZone::Middle,
+            Some(PathLossZone::Low) if above_low && above_high => PathLossZone::High,
+            Some(PathLossZone::Low) if above_low => PathLossZone::Middle,
+            Some(PathLossZone::Middle) if above_high => PathLossZone::High,
+            Some(PathLossZone::Middle) if below_low => PathLossZone::Low,
+            Some(zone) => zone,
+        };
+
+        let changed = self.zone != Some(zone);
+        self.zone = Some(zone);
+        changed.then_some(zone)
+    }
+}
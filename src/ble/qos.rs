@@ -0,0 +1,245 @@
+//! Per-connection-event link quality from the SDC's QoS connection event reports.
+//!
+//! With the vendor-specific report enabled, the controller emits one HCI vendor
+//! event per connection event that took place. The host runner hands them to
+//! [`QOS`], which aggregates them into [`LinkQuality`] windows. The interval
+//! and subrate actually in effect are derived from the event counter, so each
+//! window can be read against the requested connection rate. Changes of the
+//! effective subrate are logged, the host stack does not forward the
+//! controller's subrate change events. Outcomes are also counted per data
+//! channel over the whole connection. The gap between consecutive events is
+//! tracked report by report, so rate changes can be timed more finely than
+//! the windows allow.
+
+use core::cell::RefCell;
+
+use bt_hci::controller::ControllerCmdSync;
+use bt_hci::event::Vendor;
+use critical_section::Mutex;
+use embassy_time::{Duration, Instant};
+use nrf_sdc::vendor::QosConnEventReportEnable;
+use trouble_host::prelude::*;
+
+use crate::stats::{ChannelErrors, LinkQuality};
+
+/// Span of one aggregation window
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// `HCI_VS_SUBEVENT_QOS_CONN_EVENT_REPORT` in `sdc_hci_vs.h`
+const SUBEVENT_QOS_CONN_EVENT_REPORT: u8 = 0x80;
+
+/// One connection event as reported by the controller
+struct Report {
+    conn_handle: u16,
+    event_counter: u16,
+    crc_errors: u16,
+    naks: u16,
+    rx_timeout: bool,
+    channel_index: u8,
+}
+
+impl Report {
+    /// Subevent code, then `conn_handle: u16`, `event_counter: u16`,
+    /// `crc_error_count: u16`, `nak_count: u16`, `rx_timeout: u8`, `channel_index: u8`
+    fn parse(params: &[u8]) -> Option<Self> {
+        let (&subevent, report) = params.split_first()?;
+        if subevent != SUBEVENT_QOS_CONN_EVENT_REPORT || report.len() < 10 {
+            return None;
+        }
+
+        let u16_at = |i: usize| u16::from_le_bytes([report[i], report[i + 1]]);
+        Some(Self {
+            conn_handle: u16_at(0),
+            event_counter: u16_at(2),
+            crc_errors: u16_at(4),
+            naks: u16_at(6),
+            rx_timeout: report[8] & 0x01 != 0,
+            channel_index: report[9],
+        })
+    }
+}
+
+struct Window {
+    started: Instant,
+    first_counter: u16,
+    quality: LinkQuality,
+}
+
+impl Window {
+    fn start(report: &Report) -> Self {
+        Self {
+            started: Instant::now(),
+            first_counter: report.event_counter,
+            quality: LinkQuality::default(),
+        }
+    }
+
+    fn add(&mut self, report: &Report) {
+        let q = &mut self.quality;
+        q.events = q.events.saturating_add(1);
+        q.event_span = report.event_counter.wrapping_sub(self.first_counter);
+        if !report.rx_timeout {
+            q.events_with_rx = q.events_with_rx.saturating_add(1);
+        }
+        q.crc_errors = q.crc_errors.saturating_add(report.crc_errors);
+        q.naks = q.naks.saturating_add(report.naks);
+    }
+}
+
+struct Collector {
+    /// Connection the state belongs to
+    conn_handle: Option<u16>,
+    window: Option<Window>,
+    latest: Option<LinkQuality>,
+    /// Effective subrate factor of the last window
+    subrate: Option<u16>,
+    channels: ChannelErrors,
+    last_counter: Option<u16>,
+    /// Intervals between the last two events, and when that gap was first seen
+    gap: Option<(u16, Instant)>,
+}
+
+/// Aggregates QoS reports, passed to the host runner as its event handler
+pub struct QosCollector {
+    state: Mutex<RefCell<Collector>>,
+}
+
+pub static QOS: QosCollector = QosCollector {
+    state: Mutex::new(RefCell::new(Collector::new())),
+};
+
+impl Collector {
+    const fn new() -> Self {
+        Self {
+            conn_handle: None,
+            window: None,
+            latest: None,
+            subrate: None,
+            channels: ChannelErrors::new(),
+            last_counter: None,
+            gap: None,
+        }
+    }
+}
+
+impl QosCollector {
+    /// Forget the last connection, at every connect and disconnect
+    ///
+    /// The controller may hand a new connection the handle of the one before,
+    /// so a change of handle alone doesn't show every new connection.
+    pub fn reset(&self) {
+        critical_section::with(|cs| *self.state.borrow_ref_mut(cs) = Collector::new());
+    }
+
+    /// Last completed window
+    pub fn latest(&self) -> Option<LinkQuality> {
+        critical_section::with(|cs| self.state.borrow_ref(cs).latest)
+    }
+
+    /// Per-channel counts of the current connection
+    pub fn channel_errors(&self) -> ChannelErrors {
+        critical_section::with(|cs| self.state.borrow_ref(cs).channels)
+    }
+
+    /// Intervals between the last two connection events, and since when
+    pub fn event_gap(&self) -> Option<(u16, Instant)> {
+        critical_section::with(|cs| self.state.borrow_ref(cs).gap)
+    }
+
+    fn record(&self, report: Report) {
+        let finished = critical_section::with(|cs| {
+            let mut state = self.state.borrow_ref_mut(cs);
+
+            // A report of another connection, e.g. one that was still on its way at the reset
+            if state.conn_handle != Some(report.conn_handle) {
+                *state = Collector {
+                    conn_handle: Some(report.conn_handle),
+                    ..Collector::new()
+                };
+            }
+            if let Some(last) = state.last_counter.replace(report.event_counter) {
+                let gap = report.event_counter.wrapping_sub(last);
+                if state.gap.map(|(g, _)| g) != Some(gap) {
+                    state.gap = Some((gap, Instant::now()));
+                }
+            }
+            state
+                .channels
+                .record(report.channel_index, report.crc_errors, report.rx_timeout);
+
+            let window = state.window.get_or_insert_with(|| Window::start(&report));
+            window.add(&report);
+
+            if window.started.elapsed() < WINDOW {
+                return None;
+            }
+            let mut quality = window.quality;
+            quality.window = window.started.elapsed();
+            // The report closing this window opens the next one
+            state.window = Some(Window::start(&report));
+            state.latest = Some(quality);
+
+            let subrate = quality.subrate_factor();
+            let previous = state.subrate.replace(subrate);
+            Some((quality, previous.filter(|&p| p != subrate)))
+        });
+
+        if let Some((q, subrate_changed_from)) = finished {
+            log_window(&q);
+            if let Some(previous) = subrate_changed_from {
+                info!("Subrate changed: {} -> {}", previous, q.subrate_factor());
+            }
+        }
+    }
+}
+
+impl EventHandler for QosCollector {
+    fn on_vendor(&self, vendor: &Vendor) {
+        if let Some(report) = Report::parse(vendor.params) {
+            self.record(report);
+        }
+    }
+}
+
+fn log_window(q: &LinkQuality) {
+    let subrate = q.effective_subrate_x100();
+    info!(
+        "Link quality: {} events, interval {}us, effective subrate {}.{:02}, {} CRC errors, {} NAKs, {} RX timeouts",
+        q.events,
+        q.interval().map_or(0, |i| i.as_micros()),
+        subrate / 100,
+        subrate % 100,
+        q.crc_errors,
+        q.naks,
+        q.rx_timeouts()
+    );
+}
+
+/// Log the error rate of every channel the connection has hopped on
+pub fn log_channel_errors(errors: &ChannelErrors) {
+    info!("Per-channel errors:");
+    for (channel, c) in errors.used() {
+        let rate = c.error_rate_permille();
+        info!(
+            "  ch {}: {} events, {} failed ({}.{}%), {} CRC errors",
+            channel,
+            c.events,
+            c.failed,
+            rate / 10,
+            rate % 10,
+            c.crc_errors
+        );
+    }
+}
+
+/// Ask the controller for a report after every connection event
+pub async fn enable<C, P>(stack: &Stack<'_, C, P>)
+where
+    C: Controller + ControllerCmdSync<QosConnEventReportEnable>,
+    P: PacketPool,
+{
+    match stack.command(QosConnEventReportEnable::new(true)).await {
+        Ok(_) => info!("QoS connection event reports enabled"),
+        Err(e) => warn!("Failed to enable QoS connection event reports: {:?}", e),
+    }
+}
@@ -0,0 +1,245 @@
+//! Wake-up latency under peripheral latency and subrating.
+//!
+//! In [`TestMode::PeripheralLatency`] both sides send at random times. Data
+//! the peripheral originates is flagged with [`ORIGINATED_FLAG`] and echoed by
+//! the central, so the peripheral measures how long its data takes while it
+//! may wake up at any event. Data the central originates is answered straight
+//! away, so the central sees how long it waits for an event the peripheral
+//! listens to. [`WakeUpSweep`] walks every combination of peripheral latency
+//! and subrate factor in [`TEST_CONFIG`] and reports both distributions with
+//! the energy the model estimates for the step's traffic. After the last
+//! combination it logs them all as a latency-vs-energy table. Like the burst
+//! sweep, a step is only measured once its rate is in effect, a step that
+//! can't be put in effect is skipped and marked as such in the table.
+//!
+//! [`TestMode::PeripheralLatency`]: crate::config::TestMode::PeripheralLatency
+//! [`ORIGINATED_FLAG`]: super::ORIGINATED_FLAG
+
+use embassy_time::{Duration, Instant};
+use trouble_host::prelude::*;
+
+use super::burst::apply;
+use super::{CONN_RATE_PARAMS, SciController, rate_params};
+use crate::config::{TEST_CONFIG, WakeUpConfig};
+use crate::control::RttDistribution;
+use crate::energy::{self, EnergyEstimate, LinkModel};
+use crate::lfclk;
+use crate::stats::LatencyHistogram;
+use crate::watchdog::{self, Task};
+
+/// One combination's place in the table
+#[derive(Clone, Copy)]
+struct Row {
+    max_latency: u16,
+    subrate: u16,
+    /// `None` if the combination couldn't be put in effect
+    measured: Option<Measured>,
+}
+
+/// One combination's results
+#[derive(Clone, Copy)]
+struct Measured {
+    down_p99_us: u32,
+    up_p99_us: Option<u32>,
+    energy: EnergyEstimate,
+}
+
+/// Walks the peripheral latencies and subrate factors of [`WakeUpConfig`]
+///
+/// [`WakeUpConfig`]: crate::config::WakeUpConfig
+pub struct WakeUpSweep {
+    step: usize,
+    /// Whether the step's rate is in effect, round trips are only measured then
+    applied: bool,
+    /// Central-originated round trips of the current step
+    downlink: LatencyHistogram,
+    samples: u32,
+    started: Instant,
+    rows: [Option<Row>; WakeUpConfig::MAX_STEPS],
+}
+
+impl WakeUpSweep {
+    pub fn new() -> Self {
+        Self {
+            step: 0,
+            applied: false,
+            downlink: LatencyHistogram::new(),
+            samples: 0,
+            started: Instant::now(),
+            rows: [None; WakeUpConfig::MAX_STEPS],
+        }
+    }
+
+    fn steps() -> usize {
+        let config = &TEST_CONFIG.wake_up;
+        config.latencies.len() * config.subrate_factors.len()
+    }
+
+    /// `(max_latency, subrate_factor)` of the current step
+    fn combination(&self) -> (u16, u16) {
+        let config = &TEST_CONFIG.wake_up;
+        let subrates = config.subrate_factors.len();
+        (
+            config.latencies[self.step / subrates],
+            config.subrate_factors[self.step % subrates],
+        )
+    }
+
+    fn params(&self) -> ConnectRateParams {
+        let (max_latency, subrate) = self.combination();
+        ConnectRateParams {
+            subrate_min: subrate,
+            subrate_max: subrate,
+            max_latency,
+            ..CONN_RATE_PARAMS
+        }
+    }
+
+    /// Longest random pause before either side sends
+    pub fn max_gap() -> Duration {
+        Duration::from_millis(TEST_CONFIG.wake_up.max_gap_ms as u64)
+    }
+
+    pub fn record(&mut self, rtt: Duration) {
+        // Counted either way to pace the next attempt
+        if self.applied {
+            self.downlink.record(rtt);
+        }
+        self.samples += 1;
+    }
+
+    /// Whether the current step has all its samples
+    pub fn step_complete(&self) -> bool {
+        self.samples >= TEST_CONFIG.wake_up.samples_per_step
+    }
+
+    /// Log the step and move on, with the table after the last combination
+    ///
+    /// `peer` holds the peripheral's own round trips, if it reported them.
+    pub fn finish(&mut self, peer: Option<RttDistribution>) {
+        if self.applied {
+            self.log(peer);
+        }
+        self.next();
+    }
+
+    fn log(&mut self, peer: Option<RttDistribution>) {
+        let (max_latency, subrate) = self.combination();
+        let down = self.downlink.summary();
+        info!(
+            "Peripheral latency {}, subrate {}: central-originated n={} p50 {}us p99 {}us max {}us",
+            max_latency, subrate, down.count, down.p50_us, down.p99_us, down.max_us
+        );
+        match peer {
+            Some(up) => info!(
+                "  peripheral-originated n={} p50 {}us p99 {}us max {}us",
+                up.count, up.p50_us, up.p99_us, up.max_us
+            ),
+            None => info!("  peripheral-originated: not reported"),
+        }
+
+        // A round trip is a packet each way, whichever side started it
+        let round_trips = self.samples as u64 + peer.map_or(0, |p| p.count as u64);
+        let elapsed_us = self.started.elapsed().as_micros().max(1);
+        let packets_per_second = (2 * round_trips * 1_000_000 / elapsed_us) as u32;
+        let link = LinkModel::from_params(
+            &rate_params(&self.params()),
+            packets_per_second,
+            lfclk::active().accuracy_ppm,
+        );
+        let estimate = energy::estimate(&link);
+        estimate.log();
+
+        self.rows[self.step] = Some(Row {
+            max_latency,
+            subrate,
+            measured: Some(Measured {
+                down_p99_us: down.p99_us,
+                up_p99_us: peer.map(|p| p.p99_us),
+                energy: estimate,
+            }),
+        });
+    }
+
+    fn next(&mut self) {
+        if self.step + 1 == Self::steps() {
+            self.log_table();
+        }
+        self.step = (self.step + 1) % Self::steps();
+        self.downlink.reset();
+        self.samples = 0;
+        self.started = Instant::now();
+    }
+
+    fn log_table(&self) {
+        info!("Latency vs energy:");
+        for row in self.rows.iter().flatten() {
+            let Some(m) = &row.measured else {
+                info!(
+                    "  latency {} subrate {}: skipped",
+                    row.max_latency, row.subrate
+                );
+                continue;
+            };
+            info!(
+                "  latency {} subrate {}: p99 central-originated {}us, peripheral-originated {}us; central {}.{:02}uA, peripheral {}.{:02}uA",
+                row.max_latency,
+                row.subrate,
+                m.down_p99_us,
+                m.up_p99_us.unwrap_or(0),
+                m.energy.central_na / 1000,
+                m.energy.central_na % 1000 / 10,
+                m.energy.peripheral_na / 1000,
+                m.energy.peripheral_na % 1000 / 10
+            );
+        }
+    }
+
+    /// Put the current step's latency and subrate factor in effect, skipping
+    /// to the next step for as long as one fails
+    pub async fn request<C: SciController>(
+        &mut self,
+        stack: &Stack<'_, C, DefaultPacketPool>,
+        conn: &Connection<'_, DefaultPacketPool>,
+    ) {
+        for _ in 0..Self::steps() {
+            // Each step may wait out the subrate timeout
+            watchdog::check_in(Task::TestLoop);
+            let (max_latency, subrate) = self.combination();
+            match apply(stack, conn, &self.params()).await {
+                Ok(()) => {
+                    info!(
+                        "Wake-up step {}/{}: peripheral latency {}, subrate {}",
+                        self.step + 1,
+                        Self::steps(),
+                        max_latency,
+                        subrate
+                    );
+                    self.applied = true;
+                    self.downlink.reset();
+                    self.samples = 0;
+                    self.started = Instant::now();
+                    return;
+                }
+                Err(e) => {
+                    warn!(
+                        "Wake-up step {}/{} skipped, peripheral latency {}, subrate {}: {:?}",
+                        self.step + 1,
+                        Self::steps(),
+                        max_latency,
+                        subrate,
+                        e
+                    );
+                    self.rows[self.step] = Some(Row {
+                        max_latency,
+                        subrate,
+                        measured: None,
+                    });
+                    self.next();
+                }
+            }
+        }
+        warn!("No wake-up step could be applied, round trips aren't measured until one is");
+        self.applied = false;
+    }
+}
@@ -0,0 +1,349 @@
+pub use common::config::{
+    BurstConfig, ChannelMask, LatencyBudget, LogLevel, Originator, PingPrimitives, PingRequest,
+    PingResponse, TestMode,
+};
+
+/// Source of the 32.768 kHz sleep clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LfClockSource {
+    /// Internal RC oscillator, periodically calibrated against the HFXO
+    Rc,
+    /// External 32.768 kHz crystal
+    Xtal,
+    /// Synthesized from the HFXO, which then keeps running during sleep
+    Synth,
+}
+
+impl LfClockSource {
+    pub const ALL: [Self; 3] = [Self::Rc, Self::Xtal, Self::Synth];
+
+    /// Worst-case accuracy to declare when nothing better is known
+    pub const fn default_accuracy_ppm(self) -> u16 {
+        match self {
+            // Calibrated LFRC tolerance from the nRF52840 datasheet
+            Self::Rc => 500,
+            // Typical 32 kHz crystal and HFXO tolerances plus margin
+            Self::Xtal | Self::Synth => 50,
+        }
+    }
+}
+
+/// Sleep clock source together with the accuracy declared to the peer
+///
+/// The declared accuracy drives the peer's window widening, so it must not be
+/// better than the real one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LfClock {
+    pub source: LfClockSource,
+    pub accuracy_ppm: u16,
+}
+
+impl LfClock {
+    pub const fn new(source: LfClockSource) -> Self {
+        Self {
+            source,
+            accuracy_ppm: source.default_accuracy_ppm(),
+        }
+    }
+}
+
+/// Sleep clock(s) used over a run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LfClockPlan {
+    Fixed(LfClock),
+    /// Cycle through all sources at their default accuracy, resetting after
+    /// `pings_per_source` pings on each. Driven by the central, the peripheral
+    /// stays on the first source.
+    Compare {
+        pings_per_source: u32,
+    },
+}
+
+/// Path-loss zone boundaries in dB, with LE Power Control semantics
+///
+/// A zone is only left once the path loss is past the threshold by more than
+/// the hysteresis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathLossZones {
+    pub high_threshold: u8,
+    pub high_hysteresis: u8,
+    pub low_threshold: u8,
+    pub low_hysteresis: u8,
+}
+
+/// Radio settings of both sides
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RadioConfig {
+    /// TX power of the central's connection, the controller picks the nearest supported level
+    pub central_tx_power_dbm: i8,
+    /// TX power the central applies to the peripheral's side of the connection
+    pub peripheral_tx_power_dbm: i8,
+    /// Track path-loss zones on the central from RSSI and the peer's TX power
+    pub path_loss: Option<PathLossZones>,
+    /// Host channel classification applied by the central before it connects
+    pub channels: ChannelMask,
+}
+
+/// How the central brings a new link up to speed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BringUp {
+    /// Every procedure one after the other, with fixed pauses
+    Serial,
+    /// Link-layer procedures alongside GATT setup, waiting for completion events
+    Overlapped,
+}
+
+/// How the central's links get to the target connection rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConnectStrategy {
+    /// Legacy create connection with the host's default parameters, then
+    /// update PHY and parameters on the established link
+    UpdateAfterConnect,
+    /// Extended create connection with parameters derived from the target
+    /// rate, initiating on LE 1M like the legacy one
+    Direct,
+}
+
+/// Connection Subrating parameters, as carried by the HCI subrate commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SubrateParams {
+    pub subrate_min: u16,
+    pub subrate_max: u16,
+    pub max_latency: u16,
+    pub continuation_number: u16,
+    pub supervision_timeout_ms: u16,
+}
+
+/// Procedure the central uses to reach the target rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RateControl {
+    /// Shorter Connection Intervals connection rate request
+    ConnectionRate,
+    /// Classic Connection Subrating on the 7.5 ms base interval
+    Subrate(SubrateParams),
+}
+
+/// Peripheral latency wake-up test, swept over latencies and subrate factors
+///
+/// Each combination is requested as a connection rate on the target interval
+/// and measured over `samples_per_step` central-originated round trips.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WakeUpConfig {
+    pub latencies: &'static [u16],
+    pub subrate_factors: &'static [u16],
+    pub samples_per_step: u32,
+    /// Both sides send after a random pause of up to this long
+    pub max_gap_ms: u32,
+}
+
+impl WakeUpConfig {
+    /// Combinations kept for the latency-vs-energy table
+    pub const MAX_STEPS: usize = 16;
+}
+
+/// How the central paces its exchanges
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TrafficPattern {
+    /// The next exchange as soon as the previous one is done
+    Continuous,
+    /// `exchanges` back to back, then a pause
+    OnOff { exchanges: u32, pause_ms: u32 },
+}
+
+/// Traffic-driven switching between a low-latency and a low-power profile
+///
+/// The low-latency profile is the target connection rate, the low-power
+/// profile subrates it. Activity threshold, idle timer and minimum dwell time
+/// together keep the link from flapping between the two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveConfig {
+    /// Exchanges within `activity_window_ms` that bring the link back to low latency
+    pub activity_exchanges: u32,
+    pub activity_window_ms: u32,
+    /// Time without exchanges before the link drops to low power
+    pub idle_ms: u32,
+    /// Shortest stay in a profile before the next switch
+    pub min_dwell_ms: u32,
+    pub low_power_subrate: u16,
+    pub low_power_latency: u16,
+    pub low_power_continuation: u16,
+}
+
+/// What the peripheral does with a rate outside its [`RatePolicy`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Pushback {
+    /// Keep the last rate it accepted
+    Reject,
+    /// Ask for the closest rate within bounds instead
+    CounterPropose,
+}
+
+/// Bounds the peripheral holds connection rates to, whoever asks for them
+///
+/// A tight `min_interval_us` stands for a peripheral too busy to serve fast
+/// links, a tight `max_interval_us` or `max_latency` for a latency-critical
+/// phase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RatePolicy {
+    pub min_interval_us: u32,
+    pub max_interval_us: u32,
+    pub max_subrate: u16,
+    pub max_latency: u16,
+    pub pushback: Pushback,
+}
+
+/// Log levels applied at boot with the `log` backend, `defmt` filters at build time
+///
+/// `modules` sets the level of every module whose path starts with the prefix,
+/// the longest matching prefix wins. The peripheral takes a new default level
+/// over the control point.
+pub struct LogLevels {
+    pub default: LogLevel,
+    pub modules: &'static [(&'static str, LogLevel)],
+}
+
+impl LogLevels {
+    pub const MAX_MODULES: usize = 8;
+}
+
+/// Parameters of a test run
+///
+/// `mode`, `ping` and the peripheral's TX power are applied by the central to
+/// the peripheral over the control point, `lf_clock` is local to each device.
+pub struct TestConfig {
+    pub mode: TestMode,
+    pub ping: PingPrimitives,
+    pub lf_clock: LfClockPlan,
+    pub radio: RadioConfig,
+    pub bring_up: BringUp,
+    pub connect: ConnectStrategy,
+    pub rate: RateControl,
+    /// Used in [`TestMode::Burst`]
+    pub burst: BurstConfig,
+    /// Used in [`TestMode::PeripheralLatency`], shared by both sides through the build
+    pub wake_up: WakeUpConfig,
+    pub traffic: TrafficPattern,
+    /// Switch the central's links between rate profiles as traffic comes and goes
+    pub adaptive: Option<AdaptiveConfig>,
+    /// Let the central derive the connection rate from a latency budget
+    pub budget: Option<LatencyBudget>,
+    /// Let the peripheral push back on connection rates
+    pub rate_policy: Option<RatePolicy>,
+    pub log: LogLevels,
+}
+
+pub const TEST_CONFIG: TestConfig = TestConfig {
+    mode: TestMode::PingPong,
+    ping: PingPrimitives::DEFAULT,
+    lf_clock: LfClockPlan::Fixed(LfClock::new(LfClockSource::Rc)),
+    radio: RadioConfig {
+        central_tx_power_dbm: 0,
+        peripheral_tx_power_dbm: 0,
+        path_loss: None,
+        channels: ChannelMask::ALL,
+    },
+    bring_up: BringUp::Serial,
+    connect: ConnectStrategy::UpdateAfterConnect,
+    rate: RateControl::ConnectionRate,
+    burst: BurstConfig {
+        notifications: 4,
+        continuation_numbers: &[0, 1, 4],
+        subrate_factors: &[1, 2, 4],
+        bursts_per_step: 200,
+    },
+    wake_up: WakeUpConfig {
+        latencies: &[0, 4, 16],
+        subrate_factors: &[1, 4],
+        samples_per_step: 200,
+        max_gap_ms: 100,
+    },
+    traffic: TrafficPattern::Continuous,
+    adaptive: None,
+    budget: None,
+    rate_policy: None,
+    log: LogLevels {
+        default: LogLevel::Info,
+        modules: &[],
+    },
+};
+
+const _: () = assert!(
+    TEST_CONFIG.ping.supports(TEST_CONFIG.mode),
+    "read polling requires TestMode::PingPong, bursts require notifications"
+);
+
+const _: () = assert!(
+    TEST_CONFIG.burst.notifications > 0
+        && TEST_CONFIG.burst.notifications <= BurstConfig::MAX_NOTIFICATIONS,
+    "a burst has 1 to 16 notifications"
+);
+
+const _: () = assert!(
+    TEST_CONFIG.burst.valid_combinations() > 0,
+    "the burst sweep needs a continuation number below one of its subrate factors"
+);
+
+const _: () = assert!(
+    !TEST_CONFIG.wake_up.latencies.is_empty() && !TEST_CONFIG.wake_up.subrate_factors.is_empty(),
+    "the wake-up sweep needs at least one latency and subrate factor"
+);
+
+const _: () = assert!(
+    TEST_CONFIG.log.modules.len() <= LogLevels::MAX_MODULES,
+    "at most 8 module log levels"
+);
+
+const _: () = assert!(
+    TEST_CONFIG.wake_up.latencies.len() * TEST_CONFIG.wake_up.subrate_factors.len()
+        <= WakeUpConfig::MAX_STEPS,
+    "the wake-up sweep has at most 16 combinations"
+);
+
+const _: () = assert!(
+    !matches!(
+        TEST_CONFIG.traffic,
+        TrafficPattern::OnOff { exchanges: 0, .. }
+    ),
+    "on/off traffic needs at least one exchange per burst"
+);
+
+const _: () = assert!(
+    TEST_CONFIG.adaptive.is_none()
+        || (matches!(
+            TEST_CONFIG.mode,
+            TestMode::PingPong | TestMode::PeripheralPing
+        ) && matches!(TEST_CONFIG.rate, RateControl::ConnectionRate)),
+    "the adaptive policy needs connection rate requests and a mode without its own rate sweep"
+);
+
+const _: () = assert!(
+    TEST_CONFIG.budget.is_none()
+        || (TEST_CONFIG.adaptive.is_none()
+            && matches!(
+                TEST_CONFIG.mode,
+                TestMode::PingPong | TestMode::PeripheralPing
+            )
+            && matches!(TEST_CONFIG.rate, RateControl::ConnectionRate)),
+    "a latency budget replaces the target rate, which the adaptive policy and sweeps rely on"
+);
+
+const _: () = assert!(
+    TEST_CONFIG.radio.channels.count() >= ChannelMask::MIN_USED,
+    "at least two data channels must stay usable"
+);
+
+const _: () = assert!(
+    match TEST_CONFIG.rate_policy {
+        Some(p) => p.min_interval_us <= p.max_interval_us && p.max_subrate > 0,
+        None => true,
+    },
+    "a rate policy needs a non-empty interval range and a subrate factor of at least 1"
+);
@@ -0,0 +1,320 @@
+//! Crash records that survive a reset.
+//!
+//! Panics and HardFaults write a record into cortex-m-rt's `.uninit` section,
+//! which the runtime does not zero at startup, and reset the chip instead of
+//! spinning. The watchdog supervisor leaves the name of a stalled task there
+//! before the WDT fires. The next boot validates the record, pairs it with the
+//! hardware reset reason and logs it, the peripheral also exposes it in the
+//! GATT results service.
+
+#[cfg(feature = "peripheral")]
+use core::cell::Cell;
+use core::fmt::{self, Write};
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::ptr;
+
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::{ExceptionFrame, exception};
+#[cfg(feature = "peripheral")]
+use critical_section::Mutex;
+use embassy_nrf::pac;
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+/// "CRSH", marks a record written by this firmware
+const MAGIC: u32 = 0x4352_5348;
+
+const MESSAGE_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
+#[repr(u8)]
+pub enum CrashKind {
+    /// No record was stored before the last reset
+    Clean = 0x00,
+    Panic = 0x01,
+    HardFault = 0x02,
+    /// A supervised task stalled and the watchdog reset the chip
+    Watchdog = 0x03,
+}
+
+/// Raw record as stored across the reset, fixed layout without padding
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CrashRecord {
+    magic: u32,
+    kind: u8,
+    message_len: u8,
+    _reserved: [u8; 2],
+    message: [u8; MESSAGE_LEN],
+    /// Stacked `r0`, `r1`, `r2`, `r3`, `r12`, `lr`, `pc`, `xpsr`
+    frame: [u32; 8],
+    cfsr: u32,
+    hfsr: u32,
+    mmfar: u32,
+    bfar: u32,
+    checksum: u32,
+}
+
+impl CrashRecord {
+    const fn empty(kind: CrashKind) -> Self {
+        Self {
+            magic: MAGIC,
+            kind: kind as u8,
+            message_len: 0,
+            _reserved: [0; 2],
+            message: [0; MESSAGE_LEN],
+            frame: [0; 8],
+            cfsr: 0,
+            hfsr: 0,
+            mmfar: 0,
+            bfar: 0,
+            checksum: 0,
+        }
+    }
+
+    fn compute_checksum(&self) -> u32 {
+        // SAFETY: `CrashRecord` is `repr(C)` plain data without padding
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                (self as *const Self).cast::<u8>(),
+                core::mem::offset_of!(CrashRecord, checksum),
+            )
+        };
+        bytes
+            .iter()
+            .fold(MAGIC, |acc, &b| acc.rotate_left(5) ^ b as u32)
+    }
+
+    fn is_valid(&self) -> bool {
+        self.magic == MAGIC
+            && CrashKind::try_from(self.kind).is_ok()
+            && self.message_len as usize <= MESSAGE_LEN
+            && self.checksum == self.compute_checksum()
+    }
+
+    /// Seal the record and store it for the next boot
+    fn store(mut self) {
+        self.checksum = self.compute_checksum();
+        // SAFETY: written from the panic/fault path or the supervisor right before a reset,
+        // a crash interrupting the supervisor's write overwrites it with its own record
+        unsafe { ptr::write_volatile(&raw mut RECORD, MaybeUninit::new(self)) };
+    }
+
+    fn store_and_reset(self) -> ! {
+        self.store();
+        SCB::sys_reset()
+    }
+
+    fn with_message(mut self, args: fmt::Arguments) -> Self {
+        let mut writer = MessageWriter {
+            buf: &mut self.message,
+            len: 0,
+        };
+        let _ = writer.write_fmt(args);
+        self.message_len = writer.len as u8;
+        self
+    }
+}
+
+#[unsafe(link_section = ".uninit.CRASH_RECORD")]
+static mut RECORD: MaybeUninit<CrashRecord> = MaybeUninit::uninit();
+
+/// What the previous run left behind, taken once at boot
+#[cfg(feature = "peripheral")]
+static BOOT_REPORT: Mutex<Cell<Option<CrashReport>>> = Mutex::new(Cell::new(None));
+
+/// Crash record of the previous run together with the hardware reset reason
+#[derive(Clone, Copy)]
+pub struct CrashReport {
+    record: CrashRecord,
+    /// nRF52840 `POWER.RESETREAS` as found at boot
+    pub reset_reason: u32,
+}
+
+impl CrashReport {
+    /// `kind: u8`, `reset_reason: u32`, `pc: u32`, `lr: u32`, `xpsr: u32`,
+    /// `cfsr: u32`, `hfsr: u32`, `mmfar: u32`, `bfar: u32`, `message_len: u8`,
+    /// `message: [u8; 64]` (UTF-8, zero padded)
+    #[cfg(feature = "peripheral")]
+    pub const LEN: usize = 1 + 8 * 4 + 1 + MESSAGE_LEN;
+
+    pub fn kind(&self) -> CrashKind {
+        CrashKind::try_from(self.record.kind).unwrap_or(CrashKind::Clean)
+    }
+
+    pub fn message(&self) -> &str {
+        let bytes = &self.record.message[..self.record.message_len as usize];
+        // The writer truncates on a char boundary, so this only fails on a corrupt record
+        core::str::from_utf8(bytes).unwrap_or("<invalid utf-8>")
+    }
+
+    #[cfg(feature = "peripheral")]
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let r = &self.record;
+        let mut out = [0; Self::LEN];
+        out[0] = r.kind;
+        let words = [
+            self.reset_reason,
+            r.frame[6],
+            r.frame[5],
+            r.frame[7],
+            r.cfsr,
+            r.hfsr,
+            r.mmfar,
+            r.bfar,
+        ];
+        for (chunk, value) in out[1..33].chunks_exact_mut(4).zip(words) {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        out[33] = r.message_len;
+        out[34..].copy_from_slice(&r.message);
+        out
+    }
+}
+
+/// Name of the first reset source set in `POWER.RESETREAS`
+fn describe_reset_reason(resetreas: u32) -> &'static str {
+    const SOURCES: [(u32, &str); 9] = [
+        (1 << 0, "reset pin"),
+        (1 << 1, "watchdog"),
+        (1 << 2, "soft reset"),
+        (1 << 3, "CPU lockup"),
+        (1 << 16, "wake from System OFF (GPIO)"),
+        (1 << 17, "wake from System OFF (LPCOMP)"),
+        (1 << 18, "wake from System OFF (debug interface)"),
+        (1 << 19, "wake from System OFF (NFC)"),
+        (1 << 20, "wake from System OFF (VBUS)"),
+    ];
+
+    SOURCES
+        .iter()
+        .find(|(bit, _)| resetreas & bit != 0)
+        .map_or("power-on", |(_, name)| name)
+}
+
+/// Pick up the previous run's crash record and reset reason, then clear both
+///
+/// Must run once at boot, after logging is up.
+pub fn init() {
+    let resetreas = pac::POWER.resetreas().read().0;
+    // Reset reasons are sticky until cleared by writing ones
+    pac::POWER
+        .resetreas()
+        .write_value(pac::power::regs::Resetreas(resetreas));
+
+    // SAFETY: nothing else touches the record this early, any bit pattern is a valid `CrashRecord`
+    let record = unsafe {
+        let record = ptr::read_volatile(&raw const RECORD).assume_init();
+        ptr::write_volatile(
+            &raw mut RECORD,
+            MaybeUninit::new(CrashRecord {
+                magic: 0,
+                ..CrashRecord::empty(CrashKind::Clean)
+            }),
+        );
+        record
+    };
+
+    let report = CrashReport {
+        record: if record.is_valid() {
+            record
+        } else {
+            CrashRecord::empty(CrashKind::Clean)
+        },
+        reset_reason: resetreas,
+    };
+
+    info!(
+        "Reset reason: {} ({:#010x})",
+        describe_reset_reason(report.reset_reason),
+        report.reset_reason
+    );
+    match report.kind() {
+        CrashKind::Clean => {}
+        CrashKind::Panic => warn!("Previous run panicked: {}", report.message()),
+        CrashKind::Watchdog => warn!("Previous run reset by the watchdog: {}", report.message()),
+        CrashKind::HardFault => {
+            let r = &report.record;
+            warn!(
+                "Previous run hit a HardFault: pc={:#010x} lr={:#010x} xpsr={:#010x} cfsr={:#010x} hfsr={:#010x} mmfar={:#010x} bfar={:#010x}",
+                r.frame[6], r.frame[5], r.frame[7], r.cfsr, r.hfsr, r.mmfar, r.bfar
+            );
+        }
+    }
+
+    const RESETREAS_DOG: u32 = 1 << 1;
+    if resetreas & RESETREAS_DOG != 0 && report.kind() == CrashKind::Clean {
+        warn!("Watchdog reset without a stall record, the executor itself was blocked");
+    }
+
+    #[cfg(feature = "peripheral")]
+    critical_section::with(|cs| BOOT_REPORT.borrow(cs).set(Some(report)));
+}
+
+/// Report collected by [`init`], an empty one if it has not run
+#[cfg(feature = "peripheral")]
+pub fn boot_report() -> CrashReport {
+    critical_section::with(|cs| BOOT_REPORT.borrow(cs).get()).unwrap_or(CrashReport {
+        record: CrashRecord::empty(CrashKind::Clean),
+        reset_reason: 0,
+    })
+}
+
+/// Store the panic message and reset
+pub fn record_panic(info: &PanicInfo) -> ! {
+    CrashRecord::empty(CrashKind::Panic)
+        .with_message(format_args!("{}", info))
+        .store_and_reset()
+}
+
+/// Store the name of a stalled task ahead of the watchdog reset
+pub fn record_stall(task: &str) {
+    CrashRecord::empty(CrashKind::Watchdog)
+        .with_message(format_args!("{} stalled", task))
+        .store()
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    let mut record = CrashRecord::empty(CrashKind::HardFault);
+    record.frame = [
+        frame.r0(),
+        frame.r1(),
+        frame.r2(),
+        frame.r3(),
+        frame.r12(),
+        frame.lr(),
+        frame.pc(),
+        frame.xpsr(),
+    ];
+
+    // SAFETY: read-only access to the fault status registers
+    let scb = unsafe { &*SCB::PTR };
+    record.cfsr = scb.cfsr.read();
+    record.hfsr = scb.hfsr.read();
+    record.mmfar = scb.mmfar.read();
+    record.bfar = scb.bfar.read();
+
+    record.store_and_reset()
+}
+
+/// `fmt::Write` into a fixed buffer, silently truncating on a char boundary
+struct MessageWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for MessageWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let mut utf8 = [0; 4];
+            let encoded = c.encode_utf8(&mut utf8).as_bytes();
+            if self.len + encoded.len() > self.buf.len() {
+                return Err(fmt::Error);
+            }
+            self.buf[self.len..self.len + encoded.len()].copy_from_slice(encoded);
+            self.len += encoded.len();
+        }
+        Ok(())
+    }
+}
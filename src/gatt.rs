@@ -1,14 +1,56 @@
 use trouble_host::prelude::*;
 
+use crate::control::CONTROL_POINT_LEN;
+use crate::crash::CrashReport;
+use crate::stats::{LatencySummary, LinkParams, LinkQuality, PacketCounters, Signal};
+
 #[gatt_server]
 pub struct CounterServer {
     pub counter_service: CounterService,
+    pub results_service: ResultsService,
 }
 
 #[gatt_service(uuid = "0000ffe0-0000-1000-8000-00805f9b34fb")]
 pub struct CounterService {
-    #[characteristic(uuid = "0000ffe1-0000-1000-8000-00805f9b34fb", read, notify)]
+    #[characteristic(uuid = "0000ffe1-0000-1000-8000-00805f9b34fb", read, notify, indicate)]
     pub counter: u32,
-    #[characteristic(uuid = "0000ffe2-0000-1000-8000-00805f9b34fb", write)]
+    #[characteristic(
+        uuid = "0000ffe2-0000-1000-8000-00805f9b34fb",
+        write,
+        write_without_response
+    )]
     pub command: u8,
+    /// Control point, see [`crate::control`] for the protocol
+    #[characteristic(uuid = "0000ffe3-0000-1000-8000-00805f9b34fb", write, indicate)]
+    pub control_point: [u8; CONTROL_POINT_LEN],
+}
+
+/// On-device statistics, refreshed once per second while connected
+///
+/// Layouts are documented on the encoding types in [`crate::stats`]; all
+/// fields are little endian. Notifying the round-trip summary needs an ATT MTU
+/// of at least 27, reading it works with any MTU.
+#[gatt_service(uuid = "0000ffd0-0000-1000-8000-00805f9b34fb")]
+pub struct ResultsService {
+    /// [`LinkParams::encode`]
+    #[characteristic(uuid = "0000ffd1-0000-1000-8000-00805f9b34fb", read, notify)]
+    pub link_params: [u8; LinkParams::LEN],
+    /// [`LatencySummary::encode`] of the round trips initiated by the peripheral
+    #[characteristic(uuid = "0000ffd2-0000-1000-8000-00805f9b34fb", read, notify)]
+    pub rtt: [u8; LatencySummary::LEN],
+    /// [`PacketCounters::encode`]
+    #[characteristic(uuid = "0000ffd3-0000-1000-8000-00805f9b34fb", read, notify)]
+    pub packets: [u8; PacketCounters::LEN],
+    /// Seconds since boot
+    #[characteristic(uuid = "0000ffd4-0000-1000-8000-00805f9b34fb", read, notify)]
+    pub uptime: u32,
+    /// [`CrashReport::encode`] of the run before the last reset
+    #[characteristic(uuid = "0000ffd5-0000-1000-8000-00805f9b34fb", read)]
+    pub crash: [u8; CrashReport::LEN],
+    /// [`LinkQuality::encode`] of the last window of controller connection event reports
+    #[characteristic(uuid = "0000ffd6-0000-1000-8000-00805f9b34fb", read, notify)]
+    pub link_quality: [u8; LinkQuality::LEN],
+    /// [`Signal::encode`], RSSI sampled over the last refresh period
+    #[characteristic(uuid = "0000ffd7-0000-1000-8000-00805f9b34fb", read, notify)]
+    pub signal: [u8; Signal::LEN],
 }
@@ -0,0 +1,22 @@
+//! The controller's supported connection intervals, once read.
+//!
+//! Decoding lives in [`common::intervals`], this keeps the table the bring-up
+//! read so the rate logic can check requests against it.
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+
+pub use common::intervals::*;
+
+static SUPPORTED: Mutex<Cell<Option<SupportedIntervals>>> = Mutex::new(Cell::new(None));
+
+/// Remember the controller's table for later checks
+pub fn set_supported(intervals: SupportedIntervals) {
+    critical_section::with(|cs| SUPPORTED.borrow(cs).set(Some(intervals)));
+}
+
+/// The controller's table, once it has been read
+pub fn supported() -> Option<SupportedIntervals> {
+    critical_section::with(|cs| SUPPORTED.borrow(cs).get())
+}
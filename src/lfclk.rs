@@ -0,0 +1,169 @@
+//! Sleep clock selection and the source comparison run.
+//!
+//! MPSL fixes the LF clock source at startup, so [`LfClockPlan::Compare`] runs
+//! one source per boot. The central hands each run's results to
+//! [`finish_run`], which stores them in RAM that survives the reset and boots
+//! into the next source. Once every source has run, the next boot logs the
+//! results side by side and starts over.
+
+use core::cell::Cell;
+use core::mem::MaybeUninit;
+use core::ptr;
+
+use critical_section::Mutex;
+
+use crate::config::{LfClock, LfClockPlan, LfClockSource, TEST_CONFIG};
+
+/// "LFCK", marks a comparison state written by this firmware
+const MAGIC: u32 = 0x4c46_434b;
+
+const SOURCES: usize = LfClockSource::ALL.len();
+
+/// Results of one source's run, plain words so it can be retained as is
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RunResult {
+    pub pings: u32,
+    /// Ping loops that ended in an error, i.e. the link broke
+    pub link_losses: u32,
+    pub duration_ms: u32,
+    pub rtt_mean_us: u32,
+    pub rtt_p99_us: u32,
+    pub rtt_max_us: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CompareState {
+    magic: u32,
+    /// Index into [`LfClockSource::ALL`] of the source to run next
+    next: u32,
+    results: [RunResult; SOURCES],
+    checksum: u32,
+}
+
+impl CompareState {
+    const fn new() -> Self {
+        Self {
+            magic: MAGIC,
+            next: 0,
+            results: [RunResult {
+                pings: 0,
+                link_losses: 0,
+                duration_ms: 0,
+                rtt_mean_us: 0,
+                rtt_p99_us: 0,
+                rtt_max_us: 0,
+            }; SOURCES],
+            checksum: 0,
+        }
+    }
+
+    fn compute_checksum(&self) -> u32 {
+        // SAFETY: `CompareState` is `repr(C)` plain data without padding
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                (self as *const Self).cast::<u8>(),
+                core::mem::offset_of!(CompareState, checksum),
+            )
+        };
+        bytes
+            .iter()
+            .fold(MAGIC, |acc, &b| acc.rotate_left(5) ^ b as u32)
+    }
+
+    /// Retained state, a fresh one if there is none
+    fn load() -> Self {
+        // SAFETY: only accessed from thread mode, any bit pattern is a valid `CompareState`
+        let state = unsafe { ptr::read_volatile(&raw const STATE).assume_init() };
+        if state.magic == MAGIC
+            && (state.next as usize) <= SOURCES
+            && state.checksum == state.compute_checksum()
+        {
+            state
+        } else {
+            Self::new()
+        }
+    }
+
+    fn store(mut self) {
+        self.checksum = self.compute_checksum();
+        // SAFETY: only accessed from thread mode
+        unsafe { ptr::write_volatile(&raw mut STATE, MaybeUninit::new(self)) };
+    }
+}
+
+#[unsafe(link_section = ".uninit.LFCLK_COMPARE")]
+static mut STATE: MaybeUninit<CompareState> = MaybeUninit::uninit();
+
+static ACTIVE: Mutex<Cell<Option<LfClock>>> = Mutex::new(Cell::new(None));
+
+/// Resolve the sleep clock for this boot, must run once before MPSL starts
+pub fn select() -> LfClock {
+    let clock = match TEST_CONFIG.lf_clock {
+        LfClockPlan::Fixed(clock) => clock,
+        LfClockPlan::Compare { pings_per_source } => {
+            let mut state = CompareState::load();
+            if state.next as usize == SOURCES {
+                log_comparison(&state, pings_per_source);
+                state = CompareState::new();
+            }
+            state.store();
+            LfClock::new(LfClockSource::ALL[state.next as usize])
+        }
+    };
+
+    critical_section::with(|cs| ACTIVE.borrow(cs).set(Some(clock)));
+    clock
+}
+
+/// Clock chosen by [`select`]
+pub fn active() -> LfClock {
+    critical_section::with(|cs| ACTIVE.borrow(cs).get())
+        .expect("lfclk::select runs before the BLE stack")
+}
+
+/// `(run, runs)` of the comparison this boot belongs to, `None` with a fixed clock
+pub fn comparison_run() -> Option<(usize, usize)> {
+    match TEST_CONFIG.lf_clock {
+        LfClockPlan::Fixed(_) => None,
+        LfClockPlan::Compare { .. } => Some((CompareState::load().next as usize + 1, SOURCES)),
+    }
+}
+
+fn log_comparison(state: &CompareState, pings_per_source: u32) {
+    info!(
+        "LF clock comparison, {} pings per source:",
+        pings_per_source
+    );
+    for (source, r) in LfClockSource::ALL.into_iter().zip(state.results) {
+        info!(
+            "  {:?} ({} ppm): {} pings in {}ms, {} link losses, RTT mean {}us p99 {}us max {}us",
+            source,
+            source.default_accuracy_ppm(),
+            r.pings,
+            r.duration_ms,
+            r.link_losses,
+            r.rtt_mean_us,
+            r.rtt_p99_us,
+            r.rtt_max_us
+        );
+    }
+}
+
+/// Store the results of this boot's source and reset into the next one
+#[cfg(feature = "central")]
+pub fn finish_run(result: RunResult) -> ! {
+    let mut state = CompareState::load();
+    state.results[state.next as usize] = result;
+    state.next += 1;
+    state.store();
+
+    info!(
+        "LF clock run on {:?} done, resetting into the next source",
+        active().source
+    );
+    #[cfg(feature = "log")]
+    crate::logger::drain();
+    cortex_m::peripheral::SCB::sys_reset()
+}
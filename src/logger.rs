@@ -0,0 +1,346 @@
+//! `log` backend that keeps RTT output out of the measurement paths.
+//!
+//! A log call only renders its message into a slot of a lock-free bounded
+//! queue, stamped with [`Instant::now`]. Prefix formatting and the RTT write
+//! happen in [`flush_task`], which only runs once the latency-critical tasks
+//! yield. When the queue is full the record is dropped and counted instead of
+//! blocking the caller.
+//!
+//! The message itself still has to be rendered at the call site, `log` hands
+//! out borrowed [`core::fmt::Arguments`] only.
+//!
+//! Levels start out as [`TEST_CONFIG`] sets them and can be changed at runtime,
+//! globally with [`set_level`] and per module prefix with [`set_module_level`].
+
+use core::cell::{RefCell, UnsafeCell};
+use core::fmt::{self, Write};
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicU8, AtomicU32, AtomicUsize, Ordering};
+use core::task::Poll;
+
+use critical_section::Mutex;
+use embassy_sync::waitqueue::AtomicWaker;
+use embassy_time::Instant;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use rtt_target::{rprintln, rtt_init_print};
+use thiserror::Error;
+
+use crate::config::{LogLevel, LogLevels, TEST_CONFIG};
+
+/// Queue depth, must be a power of two
+const SLOTS: usize = 32;
+/// Fits the longest lines, the crash record and the sweep tables, with every field at full width
+const MESSAGE_LEN: usize = 160;
+const MODULE_FILTERS: usize = LogLevels::MAX_MODULES;
+
+/// Level of modules without a filter until the configuration is applied
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+struct Entry {
+    timestamp_us: u64,
+    level: Level,
+    module: &'static str,
+    truncated: bool,
+    len: u8,
+    message: [u8; MESSAGE_LEN],
+}
+
+/// Queue slot, `seq` tells producers and the consumer whose turn it is
+struct Slot {
+    seq: AtomicUsize,
+    entry: UnsafeCell<Entry>,
+}
+
+/// Bounded multi-producer queue after Dmitry Vyukov's array queue
+struct RecordQueue {
+    slots: [Slot; SLOTS],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+// SAFETY: a slot's entry is only accessed by the side that won it through `seq`
+unsafe impl Sync for RecordQueue {}
+
+impl RecordQueue {
+    const fn new() -> Self {
+        let mut slots = [const {
+            Slot {
+                seq: AtomicUsize::new(0),
+                entry: UnsafeCell::new(Entry {
+                    timestamp_us: 0,
+                    level: Level::Info,
+                    module: "",
+                    truncated: false,
+                    len: 0,
+                    message: [0; MESSAGE_LEN],
+                }),
+            }
+        }; SLOTS];
+
+        let mut i = 0;
+        while i < SLOTS {
+            slots[i].seq = AtomicUsize::new(i);
+            i += 1;
+        }
+
+        Self {
+            slots,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Claim a slot and fill it, `false` if the queue is full
+    fn push(&self, fill: impl FnOnce(&mut Entry)) -> bool {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % SLOTS];
+            let seq = slot.seq.load(Ordering::Acquire);
+
+            if seq == pos {
+                match self.head.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // SAFETY: winning the CAS gives exclusive access until `seq` is published
+                        fill(unsafe { &mut *slot.entry.get() });
+                        slot.seq.store(pos.wrapping_add(1), Ordering::Release);
+                        return true;
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if (seq as isize).wrapping_sub(pos as isize) < 0 {
+                // The consumer has not released this slot yet
+                return false;
+            } else {
+                pos = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Hand the oldest record to `read`, `false` if none is ready
+    ///
+    /// Single consumer only.
+    fn pop(&self, read: impl FnOnce(&Entry)) -> bool {
+        let pos = self.tail.load(Ordering::Relaxed);
+        let slot = &self.slots[pos % SLOTS];
+
+        if slot.seq.load(Ordering::Acquire) != pos.wrapping_add(1) {
+            return false;
+        }
+
+        // SAFETY: the producer published this slot and will not touch it until released
+        read(unsafe { &*slot.entry.get() });
+        slot.seq.store(pos.wrapping_add(SLOTS), Ordering::Release);
+        self.tail.store(pos.wrapping_add(1), Ordering::Relaxed);
+        true
+    }
+}
+
+static QUEUE: RecordQueue = RecordQueue::new();
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+static GLOBAL_LEVEL: AtomicU8 = AtomicU8::new(DEFAULT_LEVEL as u8);
+static MODULE_LEVELS: Mutex<RefCell<[Option<(&'static str, LevelFilter)>; MODULE_FILTERS]>> =
+    Mutex::new(RefCell::new([None; MODULE_FILTERS]));
+
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[error("all {MODULE_FILTERS} module filters are in use")]
+pub struct FilterTableFull;
+
+fn level_from_u8(level: u8) -> LevelFilter {
+    LevelFilter::iter()
+        .find(|&l| l as u8 == level)
+        .unwrap_or(DEFAULT_LEVEL)
+}
+
+fn filter(level: LogLevel) -> LevelFilter {
+    match level {
+        LogLevel::Off => LevelFilter::Off,
+        LogLevel::Error => LevelFilter::Error,
+        LogLevel::Warn => LevelFilter::Warn,
+        LogLevel::Info => LevelFilter::Info,
+        LogLevel::Debug => LevelFilter::Debug,
+        LogLevel::Trace => LevelFilter::Trace,
+    }
+}
+
+/// Set the level for every module without its own filter
+pub fn set_level(level: LogLevel) {
+    GLOBAL_LEVEL.store(filter(level) as u8, Ordering::Relaxed);
+    update_max_level();
+}
+
+/// Set the level for all modules whose path starts with `prefix`
+///
+/// The longest matching prefix wins. Replaces an existing filter for the same prefix.
+pub fn set_module_level(prefix: &'static str, level: LogLevel) -> Result<(), FilterTableFull> {
+    critical_section::with(|cs| {
+        let mut filters = MODULE_LEVELS.borrow_ref_mut(cs);
+        let slot = match filters
+            .iter()
+            .position(|f| f.is_some_and(|(p, _)| p == prefix))
+        {
+            Some(i) => i,
+            None => filters
+                .iter()
+                .position(Option::is_none)
+                .ok_or(FilterTableFull)?,
+        };
+        filters[slot] = Some((prefix, filter(level)));
+        Ok(())
+    })?;
+    update_max_level();
+    Ok(())
+}
+
+/// Records dropped because the queue was full since boot
+pub fn dropped() -> u32 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Let `log` skip formatting for anything no filter would accept
+fn update_max_level() {
+    let global = level_from_u8(GLOBAL_LEVEL.load(Ordering::Relaxed));
+    let max = critical_section::with(|cs| {
+        MODULE_LEVELS
+            .borrow_ref(cs)
+            .iter()
+            .flatten()
+            .map(|&(_, level)| level)
+            .fold(global, LevelFilter::max)
+    });
+    log::set_max_level(max);
+}
+
+fn level_for(module: &str) -> LevelFilter {
+    let module_level = critical_section::with(|cs| {
+        MODULE_LEVELS
+            .borrow_ref(cs)
+            .iter()
+            .flatten()
+            .filter(|(prefix, _)| module.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|&(_, level)| level)
+    });
+    module_level.unwrap_or_else(|| level_from_u8(GLOBAL_LEVEL.load(Ordering::Relaxed)))
+}
+
+struct QueueLogger;
+
+impl Log for QueueLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let timestamp_us = Instant::now().as_micros();
+        let pushed = QUEUE.push(|entry| {
+            let mut writer = MessageWriter {
+                buf: &mut entry.message,
+                len: 0,
+            };
+            entry.truncated = writer.write_fmt(*record.args()).is_err();
+            entry.len = writer.len as u8;
+            entry.timestamp_us = timestamp_us;
+            entry.level = record.level();
+            entry.module = record.module_path_static().unwrap_or("?");
+        });
+
+        if pushed {
+            WAKER.wake();
+        } else {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn flush(&self) {
+        drain();
+    }
+}
+
+static LOGGER: QueueLogger = QueueLogger;
+
+pub fn init() {
+    rtt_init_print!();
+    log::set_logger(&LOGGER).unwrap();
+
+    set_level(TEST_CONFIG.log.default);
+    for &(prefix, level) in TEST_CONFIG.log.modules {
+        if let Err(e) = set_module_level(prefix, level) {
+            warn!("Log level for {} not set: {}", prefix, e);
+        }
+    }
+}
+
+fn print(entry: &Entry) {
+    let message =
+        core::str::from_utf8(&entry.message[..entry.len as usize]).unwrap_or("<invalid utf-8>");
+    rprintln!(
+        "[{:>6}.{:06} {:<5} {}] {}{}",
+        entry.timestamp_us / 1_000_000,
+        entry.timestamp_us % 1_000_000,
+        entry.level,
+        entry.module,
+        message,
+        if entry.truncated { "..." } else { "" }
+    );
+}
+
+/// Print everything queued so far, e.g. from the panic handler
+pub fn drain() {
+    while QUEUE.pop(print) {}
+}
+
+/// Print queued records whenever the other tasks leave time for it
+#[embassy_executor::task]
+pub async fn flush_task() -> ! {
+    let mut reported_dropped = 0;
+    loop {
+        poll_fn(|cx| {
+            WAKER.register(cx.waker());
+            if QUEUE.pop(print) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+        drain();
+
+        let dropped = dropped();
+        if dropped != reported_dropped {
+            rprintln!("[logger] {} records dropped", dropped - reported_dropped);
+            reported_dropped = dropped;
+        }
+    }
+}
+
+/// `fmt::Write` into a fixed buffer, `Err` once it is full
+struct MessageWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for MessageWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let mut utf8 = [0; 4];
+            let encoded = c.encode_utf8(&mut utf8).as_bytes();
+            if self.len + encoded.len() > self.buf.len() {
+                return Err(fmt::Error);
+            }
+            self.buf[self.len..self.len + encoded.len()].copy_from_slice(encoded);
+            self.len += encoded.len();
+        }
+        Ok(())
+    }
+}
@@ -1,55 +1,64 @@
 #![no_std]
 #![no_main]
 
+use common::{control, stats};
+#[cfg(feature = "central")]
+use common::{energy, solver};
+#[cfg(feature = "defmt")]
+use defmt_rtt as _;
 use embassy_executor::Spawner;
-use log::{LevelFilter, info};
-use rtt_target::{rprintln, rtt_init_print};
+
+// The logging macros, with the backend selected through `common/log` or `common/defmt`
+#[macro_use]
+extern crate common;
 
 mod ble;
+mod config;
+mod crash;
 #[cfg(feature = "peripheral")]
 mod gatt;
+mod intervals;
+mod lfclk;
+#[cfg(feature = "log")]
+mod logger;
 mod nrf;
+mod random;
+mod watchdog;
 
 use nrf::*;
 
 // --- Panic handler ---
 #[panic_handler]
 fn panic(e: &core::panic::PanicInfo) -> ! {
-    rprintln!("PANIC: {}", e);
-    loop {}
-}
-
-// --- RTT Logger ---
-struct RttLogger;
-impl log::Log for RttLogger {
-    fn enabled(&self, metadata: &log::Metadata) -> bool {
-        metadata.level() <= LevelFilter::Info
-    }
-    fn log(&self, record: &log::Record) {
-        if self.enabled(record.metadata()) {
-            rprintln!("[{}] {}", record.level(), record.args());
-        }
+    #[cfg(feature = "log")]
+    {
+        // Get queued records out first, they lead up to the panic
+        logger::drain();
+        rtt_target::rprintln!("PANIC: {}", e);
     }
-    fn flush(&self) {}
-}
-static LOGGER: RttLogger = RttLogger;
-
-fn init_logging() {
-    rtt_init_print!();
-    log::set_logger(&LOGGER).unwrap();
-    log::set_max_level(LevelFilter::Info);
+    #[cfg(feature = "defmt")]
+    defmt::error!("PANIC: {}", defmt::Display2Format(e));
+    crash::record_panic(e)
 }
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
-    init_logging();
+    #[cfg(feature = "log")]
+    {
+        logger::init();
+        spawner.spawn(logger::flush_task()).unwrap();
+    }
+    crash::init();
 
     let p = embassy_nrf::init(Default::default());
     info!("Embassy initialized!");
 
+    spawner.spawn(watchdog::supervisor(p.WDT)).unwrap();
+
     // init BLE Controller
     let ble_resources = take_ble_resources!(p);
-    let sdc = nrf::init_ble(ble_resources, spawner);
+    let sdc = nrf::init_ble(ble_resources, spawner)
+        .unwrap_or_else(|e| panic!("BLE controller init failed: {}", e));
 
     // Run BLE stack
     ble::run(sdc).await;
@@ -1,9 +1,16 @@
+use embassy_futures::select::{Either, select};
 use embassy_nrf::{Peri, bind_interrupts, peripherals::*, rng};
 use macros::take_resources;
 use nrf_sdc::mpsl::{self, MultiprotocolServiceLayer};
 use static_cell::StaticCell;
+use thiserror::Error;
 use trouble_host::prelude::*;
 
+use crate::ble::CONNECTIONS_MAX;
+use crate::config::{LfClock, LfClockSource};
+use crate::lfclk;
+use crate::watchdog::{self, Task};
+
 bind_interrupts!(struct Irqs {
     RNG => rng::InterruptHandler<RNG>;
     EGU0_SWI0 => nrf_sdc::mpsl::LowPrioInterruptHandler;
@@ -15,18 +22,41 @@ bind_interrupts!(struct Irqs {
 
 #[embassy_executor::task]
 async fn mpsl_task(mpsl: &'static MultiprotocolServiceLayer<'static>) -> ! {
-    mpsl.run().await
+    match select(mpsl.run(), watchdog::heartbeat(Task::Mpsl)).await {
+        Either::First(never) | Either::Second(never) => never,
+    }
 }
 
 const L2CAP_TXQ: u8 = 3;
 const L2CAP_RXQ: u8 = 3;
 
+// Estimates for sizing the SDC memory, `build_sdc` checks the result against
+// `Builder::required_memory` and logs the exact figure
+/// Role, advertising/initiator and feature state independent of the links
+const SDC_MEM_FIXED: usize = 2_500;
+/// Link state excluding data buffers
+const SDC_MEM_PER_LINK: usize = 3_300;
+/// One queued packet buffer including the controller's bookkeeping
+const SDC_MEM_PER_BUFFER: usize = DefaultPacketPool::MTU + 40;
+
+/// Memory reserved for the SDC, derived from the link and buffer configuration
+const SDC_MEM_SIZE: usize = SDC_MEM_FIXED
+    + CONNECTIONS_MAX * (SDC_MEM_PER_LINK + (L2CAP_TXQ + L2CAP_RXQ) as usize * SDC_MEM_PER_BUFFER);
+
+#[derive(Debug, Error)]
+pub enum SdcInitError {
+    #[error("SDC needs {required} bytes of memory, only {available} reserved")]
+    InsufficientMemory { required: usize, available: usize },
+    #[error("SDC rejected the configuration: {0:?}")]
+    Controller(#[from] nrf_sdc::Error),
+}
+
 fn build_sdc<'d, const N: usize>(
     p: nrf_sdc::Peripherals<'d>,
     rng: &'d mut rng::Rng<embassy_nrf::mode::Async>,
     mpsl: &'d MultiprotocolServiceLayer,
     mem: &'d mut nrf_sdc::Mem<N>,
-) -> Result<nrf_sdc::SoftdeviceController<'d>, nrf_sdc::Error> {
+) -> Result<nrf_sdc::SoftdeviceController<'d>, SdcInitError> {
     let mut builder = nrf_sdc::Builder::new()?;
 
     builder = builder.support_extended_feature_set().support_le_2m_phy();
@@ -35,6 +65,8 @@ fn build_sdc<'d, const N: usize>(
     #[cfg(feature = "peripheral")]
     {
         builder = builder
+            .central_count(0)?
+            .peripheral_count(CONNECTIONS_MAX as u8)?
             .support_peripheral()
             .support_phy_update_peripheral()
             .support_adv()
@@ -46,6 +78,8 @@ fn build_sdc<'d, const N: usize>(
     #[cfg(feature = "central")]
     {
         builder = builder
+            .peripheral_count(0)?
+            .central_count(CONNECTIONS_MAX as u8)?
             .support_central()
             .support_phy_update_central()
             .support_connection_subrating_central()
@@ -60,9 +94,40 @@ fn build_sdc<'d, const N: usize>(
         L2CAP_RXQ,
     )?;
 
-    let sdc = builder.build(p, rng, mpsl, mem);
+    let required = builder.required_memory()?;
+    info!(
+        "SDC memory: {} of {} bytes required ({} links, {}/{} TX/RX buffers)",
+        required, N, CONNECTIONS_MAX, L2CAP_TXQ, L2CAP_RXQ
+    );
+    if required > N {
+        return Err(SdcInitError::InsufficientMemory {
+            required,
+            available: N,
+        });
+    }
+
+    Ok(builder.build(p, rng, mpsl, mem)?)
+}
+
+fn lfclk_config(clock: LfClock) -> mpsl::raw::mpsl_clock_lfclk_cfg_t {
+    // Calibration intervals only apply to the RC oscillator and must be zero otherwise
+    let (source, rc_ctiv, rc_temp_ctiv) = match clock.source {
+        LfClockSource::Rc => (
+            mpsl::raw::MPSL_CLOCK_LF_SRC_RC,
+            mpsl::raw::MPSL_RECOMMENDED_RC_CTIV,
+            mpsl::raw::MPSL_RECOMMENDED_RC_TEMP_CTIV,
+        ),
+        LfClockSource::Xtal => (mpsl::raw::MPSL_CLOCK_LF_SRC_XTAL, 0, 0),
+        LfClockSource::Synth => (mpsl::raw::MPSL_CLOCK_LF_SRC_SYNTH, 0, 0),
+    };
 
-    sdc
+    mpsl::raw::mpsl_clock_lfclk_cfg_t {
+        source: source as u8,
+        rc_ctiv: rc_ctiv as u8,
+        rc_temp_ctiv: rc_temp_ctiv as u8,
+        accuracy_ppm: clock.accuracy_ppm,
+        skip_wait_lfclk_started: mpsl::raw::MPSL_DEFAULT_SKIP_WAIT_LFCLK_STARTED != 0,
+    }
 }
 
 #[take_resources]
@@ -91,22 +156,16 @@ pub struct BleResources<'p> {
 pub fn init_ble<'d>(
     p: BleResources<'static>,
     spawner: embassy_executor::Spawner,
-) -> nrf_sdc::SoftdeviceController<'d> {
+) -> Result<nrf_sdc::SoftdeviceController<'d>, SdcInitError> {
     let mpsl_p =
         mpsl::Peripherals::new(p.rtc0, p.timer0, p.temp, p.ppi_ch19, p.ppi_ch30, p.ppi_ch31);
 
-    let lfclk_cfg = mpsl::raw::mpsl_clock_lfclk_cfg_t {
-        source: mpsl::raw::MPSL_CLOCK_LF_SRC_RC as u8,
-        rc_ctiv: mpsl::raw::MPSL_RECOMMENDED_RC_CTIV as u8,
-        rc_temp_ctiv: mpsl::raw::MPSL_RECOMMENDED_RC_TEMP_CTIV as u8,
-        accuracy_ppm: mpsl::raw::MPSL_DEFAULT_CLOCK_ACCURACY_PPM as u16,
-        skip_wait_lfclk_started: mpsl::raw::MPSL_DEFAULT_SKIP_WAIT_LFCLK_STARTED != 0,
-    };
+    let lfclk_cfg = lfclk_config(lfclk::select());
 
     static MPSL: StaticCell<MultiprotocolServiceLayer> = StaticCell::new();
     static RNG: StaticCell<embassy_nrf::rng::Rng<'static, embassy_nrf::mode::Async>> =
         StaticCell::new();
-    static SDC_MEM: StaticCell<nrf_sdc::Mem<7_500>> = StaticCell::new();
+    static SDC_MEM: StaticCell<nrf_sdc::Mem<SDC_MEM_SIZE>> = StaticCell::new();
 
     let mpsl = MPSL.init(mpsl::MultiprotocolServiceLayer::new(mpsl_p, Irqs, lfclk_cfg).unwrap());
     spawner.spawn(mpsl_task(&*mpsl)).unwrap();
@@ -117,7 +176,7 @@ pub fn init_ble<'d>(
     );
 
     let rng = RNG.init(rng::Rng::new(p.rng, Irqs));
-    let sdc_mem = SDC_MEM.init(nrf_sdc::Mem::<7_500>::new());
+    let sdc_mem = SDC_MEM.init(nrf_sdc::Mem::<SDC_MEM_SIZE>::new());
 
-    build_sdc(sdc_p, rng, mpsl, sdc_mem).unwrap()
+    build_sdc(sdc_p, rng, mpsl, sdc_mem)
 }
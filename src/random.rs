@@ -0,0 +1,33 @@
+//! Pseudo-random pauses for randomly timed traffic.
+//!
+//! A xorshift generator seeded from the uptime is plenty to keep sends from
+//! lining up with connection events. It is not meant for anything else.
+
+use embassy_time::{Duration, Instant};
+
+pub struct Xorshift32(u32);
+
+impl Xorshift32 {
+    pub fn from_uptime() -> Self {
+        // Zero is the one state xorshift never leaves
+        Self((Instant::now().as_ticks() as u32) | 1)
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    /// Uniform pause of up to `max`, at microsecond resolution
+    pub fn pause(&mut self, max: Duration) -> Duration {
+        let max_us = max.as_micros().min(u32::MAX as u64) as u32;
+        match max_us {
+            0 => Duration::from_ticks(0),
+            max_us => Duration::from_micros((self.next_u32() % max_us) as u64),
+        }
+    }
+}
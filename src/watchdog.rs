@@ -0,0 +1,125 @@
+//! Hardware watchdog fed only while every supervised task makes progress.
+//!
+//! Each long-running task calls [`check_in`] whenever it gets work done. The
+//! supervisor feeds the WDT as long as no task has been silent for longer than
+//! [`STALL_TIMEOUT`]. Otherwise it stores the stalled task's name in the crash
+//! record and stops feeding, so the WDT resets the chip and the next boot
+//! reports which task hung.
+
+use core::future::Future;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_futures::select::{Either, select};
+use embassy_nrf::{Peri, peripherals::WDT, wdt};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+
+use crate::crash;
+
+/// Longest a supervised task may go without checking in
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval of the idle heartbeat and of the supervisor's checks
+const CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Time from the last feed until the WDT resets the chip
+const WDT_TIMEOUT_SECS: u32 = 3;
+
+/// WDT runs from the 32.768 kHz LFCLK
+const WDT_TICKS_PER_SEC: u32 = 32_768;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Task {
+    Mpsl,
+    HostRunner,
+    TestLoop,
+}
+
+impl Task {
+    const ALL: [Task; 3] = [Task::Mpsl, Task::HostRunner, Task::TestLoop];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Task::Mpsl => "mpsl_task",
+            Task::HostRunner => "host runner",
+            Task::TestLoop => "test loop",
+        }
+    }
+}
+
+/// Milliseconds since boot of each task's last check-in, indexed by [`Task`]
+static LAST_CHECK_IN: [AtomicU32; Task::ALL.len()] = [const { AtomicU32::new(0) }; Task::ALL.len()];
+
+fn now_ms() -> u32 {
+    Instant::now().as_millis() as u32
+}
+
+/// Record that `task` made progress
+pub fn check_in(task: Task) {
+    LAST_CHECK_IN[task as usize].store(now_ms(), Ordering::Relaxed);
+}
+
+/// Keep checking in for a task whose progress cannot be observed from outside
+///
+/// This only proves the task is still being polled.
+pub async fn heartbeat(task: Task) -> ! {
+    loop {
+        check_in(task);
+        Timer::after(CHECK_PERIOD).await;
+    }
+}
+
+/// Await a future that may legitimately stay pending for long, e.g. advertising
+pub async fn waiting<F: Future>(task: Task, fut: F) -> F::Output {
+    match select(fut, heartbeat(task)).await {
+        Either::First(output) => output,
+        Either::Second(never) => never,
+    }
+}
+
+fn stalled_task() -> Option<Task> {
+    let now = now_ms();
+    let timeout = STALL_TIMEOUT.as_millis() as u32;
+
+    Task::ALL.into_iter().find(|&task| {
+        let last = LAST_CHECK_IN[task as usize].load(Ordering::Relaxed);
+        now.wrapping_sub(last) > timeout
+    })
+}
+
+#[embassy_executor::task]
+pub async fn supervisor(wdt: Peri<'static, WDT>) {
+    let mut config = wdt::Config::default();
+    config.timeout_ticks = WDT_TIMEOUT_SECS * WDT_TICKS_PER_SEC;
+    // Don't reset while halted in the debugger
+    config.action_during_debug_halt = wdt::HaltConfig::PAUSE;
+
+    let (_wdt, [mut handle]) = match wdt::Watchdog::try_new(wdt, config) {
+        Ok(wdt) => wdt,
+        Err(_) => {
+            warn!("Watchdog already running with a different configuration, supervision disabled");
+            return;
+        }
+    };
+    info!(
+        "Watchdog armed: stall timeout {}s, reset {}s after the last feed",
+        STALL_TIMEOUT.as_secs(),
+        WDT_TIMEOUT_SECS
+    );
+
+    let mut ticker = Ticker::every(CHECK_PERIOD);
+    loop {
+        ticker.next().await;
+
+        if let Some(task) = stalled_task() {
+            error!(
+                "{} stalled for more than {}s, letting the watchdog reset",
+                task.name(),
+                STALL_TIMEOUT.as_secs()
+            );
+            crash::record_stall(task.name());
+            // Stop feeding, the WDT takes it from here
+            return;
+        }
+        handle.pet();
+    }
+}